@@ -0,0 +1,204 @@
+/*
+    Dynamic programming beat tracking, following:
+
+        Ellis, "Beat Tracking by Dynamic Programming", Journal of New Music
+        Research, 2007
+
+    Given an onset envelope and a target tempo, we find the sequence of beat
+    times that maximises the total onset strength at each beat, while
+    penalising deviations in the spacing between beats from the beat period.
+*/
+use dsp::onset::OnsetEnvelope;
+use estimators::context::AnalysisContext;
+use estimators::octave::OctaveCorrector;
+use estimators::onset::OnsetTempoEstimator;
+use estimators::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Beats {
+    pub bpm: f64,        // the tempo that the beats were tracked at
+    pub times: Vec<f64>, // the times (in seconds) of the beats
+}
+
+impl Beats {
+    // Format the beat times as a comma separated string, for exporting
+    pub fn format(&self) -> String {
+        self.times
+            .iter()
+            .map(|t| format!("{:.3}", t))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+pub struct BeatTracker {
+    pub tightness: f64, // how strongly to penalise irregular beat spacing
+}
+
+impl Default for BeatTracker {
+    fn default() -> BeatTracker {
+        BeatTracker { tightness: 100.0 }
+    }
+}
+
+impl BeatTracker {
+    // Leading and trailing beats weaker than this (relative to the RMS
+    // onset strength at all beats) are discarded
+    const TRIM: f64 = 0.5;
+
+    #[flame("BeatTracker")]
+    pub fn track(&self, envelope: &OnsetEnvelope, bpm: f64) -> Option<Beats> {
+        let onsets = &envelope.values;
+        let period = envelope.lag(bpm);
+        if onsets.is_empty() || !period.is_finite() || period < 1.0 {
+            return None;
+        }
+
+        // The best score of a sequence of beats ending at each frame, and the
+        // previous beat in that sequence.
+        let mut score: Vec<f64> = vec![0.0; onsets.len()];
+        let mut previous: Vec<Option<usize>> = vec![None; onsets.len()];
+        let (near, far) = (
+            (period / 2.0).round() as usize,
+            (period * 2.0).round() as usize,
+        );
+
+        for frame in 0..onsets.len() {
+            let best = (frame.saturating_sub(far)..frame.saturating_sub(near).max(1))
+                .filter(|&p| p < frame)
+                .map(|p| {
+                    let deviation = ((frame - p) as f64 / period).ln();
+                    (p, score[p] - self.tightness * deviation * deviation)
+                })
+                .fold(None, |best: Option<(usize, f64)>, next| match best {
+                    Some(b) if b.1 >= next.1 => Some(b),
+                    _ => Some(next),
+                });
+            score[frame] = onsets[frame];
+            if let Some((p, s)) = best {
+                if s > 0.0 {
+                    score[frame] += s;
+                    previous[frame] = Some(p);
+                }
+            }
+        }
+
+        // Backtrack from the best scoring frame within the last beat period.
+        let last_period = onsets.len().saturating_sub(period.ceil() as usize);
+        let mut frame = (last_period..onsets.len()).fold(last_period, |best, f| {
+            if score[f] > score[best] {
+                f
+            } else {
+                best
+            }
+        });
+        let mut frames = vec![frame];
+        while let Some(p) = previous[frame] {
+            frames.push(p);
+            frame = p;
+        }
+        frames.reverse();
+
+        // Trim weak beats from the start and end of the track (i.e. silence)
+        let rms =
+            (frames.iter().map(|f| onsets[*f].powi(2)).sum::<f64>() / frames.len() as f64).sqrt();
+        let strong = |f: &usize| onsets[*f] >= Self::TRIM * rms;
+        let first = frames.iter().position(&strong)?;
+        let last = frames.iter().rposition(&strong)?;
+
+        Some(Beats {
+            bpm,
+            times: frames[first..=last]
+                .iter()
+                .map(|f| envelope.time(*f as f64))
+                .collect(),
+        })
+    }
+
+    // Track the beats of an onset envelope, at the given tempo, or at a tempo
+    // estimated from the envelope if we don't already know it.
+    pub fn track_envelope(
+        &self,
+        envelope: &OnsetEnvelope,
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<Beats> {
+        let bpm = match bpm {
+            Some(b) => b,
+            None => {
+                let estimate = OnsetTempoEstimator::new(params).estimate(envelope)?;
+                match &params.octave_correction {
+                    Some(prior) => {
+                        OctaveCorrector::new(prior)
+                            .with_meter(params.meter)
+                            .correct(estimate)
+                            .bpm
+                    }
+                    None => estimate.bpm,
+                }
+            }
+        };
+        info!("Tracking beats at {} bpm", bpm);
+        self.track(envelope, bpm)
+    }
+
+    // Track the beats of a (mono) stream of samples
+    pub fn analyse(
+        &self,
+        samples: &[f32],
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<Beats> {
+        let envelope = OnsetEnvelope::spectral_flux(samples, SAMPLE_RATE);
+        self.track_envelope(&envelope, params, bpm)
+    }
+
+    // Track the beats of a track, reusing its onset envelope if already computed
+    pub fn run(
+        &self,
+        context: &AnalysisContext,
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<Beats> {
+        self.track_envelope(&*context.envelope()?, params, bpm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth;
+
+    // a click on every beat, starting after a second of silence
+    fn click_track(bpm: f64, seconds: f64) -> Vec<f32> {
+        let mut hits = synth::clicks(bpm, seconds);
+        hits.retain(|hit| hit.time >= 1.0);
+        synth::render(&hits, seconds)
+    }
+
+    #[test]
+    fn clicks() {
+        let tracker = BeatTracker::default();
+        let beats = tracker
+            .analyse(
+                &click_track(120.0, 20.0),
+                &EstimatorParams::default(),
+                Some(120.0),
+            )
+            .unwrap();
+        // one beat every half second, after the initial silence
+        assert!(beats.times.len() >= 36, "Too few beats: {:?}", beats.times);
+        assert!(
+            beats.times[0] >= 0.9,
+            "Beat found in silence: {:?}",
+            beats.times
+        );
+        for pair in beats.times.windows(2) {
+            assert!(
+                (pair[1] - pair[0] - 0.5).abs() < 0.03,
+                "Irregular beats: {:?}",
+                beats.times
+            );
+        }
+    }
+}
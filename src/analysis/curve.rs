@@ -0,0 +1,189 @@
+/*
+    Tempo curves. Rather than a single tempo for a whole track, we run an
+    estimator over sliding windows of the decoded audio, to see how the
+    tempo changes over the course of the track - e.g. live recordings that
+    drift, or tracks that go into double time for the last chorus.
+*/
+use estimators::*;
+use serde_json;
+use types::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CurveParams {
+    pub window: f64, // the length (in seconds) of each analysis window
+    pub hop: f64,    // the time (in seconds) between the starts of consecutive windows
+}
+
+impl Default for CurveParams {
+    fn default() -> CurveParams {
+        CurveParams {
+            window: 20.0,
+            hop: 5.0,
+        }
+    }
+}
+
+// The tempo estimated for a single window
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TempoPoint {
+    pub time: f64, // the centre of the window, in seconds
+    pub bpm: f64,
+    pub confidence: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CurveSummary {
+    pub start_bpm: f64, // the tempo at the start of the track
+    pub end_bpm: f64,   // the tempo at the end of the track
+    pub drift: f64,     // the change in tempo from start to end
+    pub min_bpm: f64,
+    pub max_bpm: f64,
+    pub mean_bpm: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TempoCurve {
+    pub algorithm: AlgorithmE, // the estimator used for each window
+    pub params: CurveParams,
+    pub points: Vec<TempoPoint>,
+    pub summary: CurveSummary,
+}
+
+impl CurveSummary {
+    // The number of windows at each end of the track used for the start/end tempo
+    const ENDS: usize = 3;
+
+    fn median(bpms: &[f64]) -> f64 {
+        let mut sorted = bpms.to_vec();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sorted[sorted.len() / 2]
+    }
+
+    // Summarise a curve. The start and end tempos are medians over the first
+    // and last few windows, so that a single bad window doesn't skew them.
+    fn from_points(points: &[TempoPoint]) -> Option<CurveSummary> {
+        if points.is_empty() {
+            return None;
+        }
+        let bpms: Vec<f64> = points.iter().map(|p| p.bpm).collect();
+        let ends = Self::ENDS.min(bpms.len());
+        let start_bpm = Self::median(&bpms[..ends]);
+        let end_bpm = Self::median(&bpms[bpms.len() - ends..]);
+        Some(CurveSummary {
+            start_bpm,
+            end_bpm,
+            drift: end_bpm - start_bpm,
+            min_bpm: bpms.iter().cloned().fold(f64::INFINITY, f64::min),
+            max_bpm: bpms.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            mean_bpm: bpms.iter().sum::<f64>() / bpms.len() as f64,
+        })
+    }
+}
+
+impl TempoCurve {
+    // Run an estimator over sliding windows of a (mono) stream of samples
+    #[flame("TempoCurve")]
+    pub fn analyse(
+        estimator: &dyn TempoEstimator,
+        algorithm: AlgorithmE,
+        samples: &[f32],
+        params: &CurveParams,
+    ) -> Option<TempoCurve> {
+        if params.window <= 0.0 || params.hop <= 0.0 {
+            error!("Invalid tempo curve parameters: {:?}", params);
+            return None;
+        }
+        let window = ((params.window * SAMPLE_RATE) as usize).min(samples.len());
+        let hop = ((params.hop * SAMPLE_RATE) as usize).max(1);
+
+        let mut points = vec![];
+        let mut start = 0;
+        while start + window <= samples.len() && window > 0 {
+            let time = (start + window / 2) as f64 / SAMPLE_RATE;
+            match estimator.run_samples(&samples[start..start + window]) {
+                Some(estimate) => points.push(TempoPoint {
+                    time,
+                    bpm: estimate.bpm,
+                    confidence: estimate.confidence,
+                }),
+                None => info!("No tempo estimated for window at {:.1}s", time),
+            }
+            start += hop;
+        }
+        debug!("Tempo curve: {:?}", points);
+
+        Some(TempoCurve {
+            algorithm,
+            params: params.clone(),
+            summary: CurveSummary::from_points(&points)?,
+            points,
+        })
+    }
+
+    pub fn format_json(&self) -> Option<String> {
+        serde_json::to_string(self).ok()
+    }
+
+    pub fn format_readable(&self) -> String {
+        let summary = &self.summary;
+        let mut output = format!(
+            "Tempo curve ({}): start {:.2}, end {:.2}, drift {:+.2}, range {:.2} - {:.2}\n",
+            self.algorithm,
+            summary.start_bpm,
+            summary.end_bpm,
+            summary.drift,
+            summary.min_bpm,
+            summary.max_bpm
+        );
+        for point in &self.points {
+            let time = point.time as u64;
+            output += &format!("  {}:{:02} {:.2}\n", time / 60, time % 60, point.bpm);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // "Estimates" the tempo of some samples as the value of the first sample
+    struct SampleValueEstimator {}
+
+    impl TempoEstimator for SampleValueEstimator {
+        fn run(&self, _audio_file: &PathBuf) -> Option<TempoEstimate> {
+            None
+        }
+
+        fn run_samples(&self, samples: &[f32]) -> Option<TempoEstimate> {
+            Some(TempoEstimate::from_bpm(samples[0] as f64, None))
+        }
+    }
+
+    #[test]
+    fn drift() {
+        // a minute at 150bpm, followed by a minute at 180bpm
+        let samples: Vec<f32> = (0..(120.0 * SAMPLE_RATE) as usize)
+            .map(|i| {
+                if i < (60.0 * SAMPLE_RATE) as usize {
+                    150.0
+                } else {
+                    180.0
+                }
+            })
+            .collect();
+        let curve = TempoCurve::analyse(
+            &SampleValueEstimator {},
+            AlgorithmE::Onset,
+            &samples,
+            &CurveParams::default(),
+        )
+        .unwrap();
+        assert_eq!(curve.points.len(), 21);
+        assert_eq!(curve.points[0].time, 10.0);
+        assert_eq!(curve.summary.start_bpm, 150.0);
+        assert_eq!(curve.summary.end_bpm, 180.0);
+        assert_eq!(curve.summary.drift, 30.0);
+    }
+}
@@ -0,0 +1,128 @@
+/*
+    Meter detection. Decides whether the beats of a track are grouped in
+    twos (or fours), or in threes (waltzes), from the pattern of accents on
+    the tracked beats. Accented beats (the downbeats, or the backbeats in
+    swing) recur every two beats in duple meter, and every three in triple.
+*/
+use analysis::beats::*;
+use dsp::autocorrelation;
+use dsp::onset::OnsetEnvelope;
+use estimators::context::AnalysisContext;
+use estimators::*;
+use types::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MeterEstimate {
+    pub meter: MeterE,
+    pub confidence: f64, // in [0, 1]
+}
+
+#[derive(Default)]
+pub struct MeterAnalyser {}
+
+impl MeterAnalyser {
+    // The fewest beats that we need to see a pattern of accents
+    const MIN_BEATS: usize = 12;
+    // How far (in frames) from a tracked beat we look for its onset
+    const SEARCH: usize = 2;
+
+    // The strength of the onset at each beat
+    fn accents(envelope: &OnsetEnvelope, beats: &Beats) -> Vec<f64> {
+        beats
+            .times
+            .iter()
+            .map(|time| {
+                let frame = envelope.frame(*time).round().max(0.0) as usize;
+                let from = frame.saturating_sub(Self::SEARCH);
+                let to = (frame + Self::SEARCH + 1).min(envelope.values.len());
+                envelope.values[from.min(to)..to]
+                    .iter()
+                    .cloned()
+                    .fold(0.0, f64::max)
+            })
+            .collect()
+    }
+
+    #[flame("MeterAnalyser")]
+    pub fn estimate(&self, envelope: &OnsetEnvelope, beats: &Beats) -> Option<MeterEstimate> {
+        if beats.times.len() < Self::MIN_BEATS {
+            info!("Too few beats to detect meter: {}", beats.times.len());
+            return None;
+        }
+        let mut accents = Self::accents(envelope, beats);
+        let mean = accents.iter().sum::<f64>() / accents.len() as f64;
+        for accent in accents.iter_mut() {
+            *accent -= mean;
+        }
+
+        // How strongly the accents repeat every two (or four), and every three beats
+        let acf = autocorrelation(&accents, 4);
+        let (duple, triple) = (acf[2].max(acf[4]), acf[3]);
+        debug!("Meter periodicity: duple {}, triple {}", duple, triple);
+        if duple <= 0.0 && triple <= 0.0 {
+            return None;
+        }
+
+        let (meter, winner, loser) = if triple > duple {
+            (MeterE::Triple, triple, duple)
+        } else {
+            (MeterE::Duple, duple, triple)
+        };
+        Some(MeterEstimate {
+            meter,
+            confidence: (winner - loser.max(0.0)) / winner,
+        })
+    }
+
+    // Detect the meter of a (mono) stream of samples, tracking the beats at
+    // the given tempo, or an estimated one if we don't know it.
+    pub fn analyse(
+        &self,
+        samples: &[f32],
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<MeterEstimate> {
+        let envelope = OnsetEnvelope::spectral_flux(samples, SAMPLE_RATE);
+        let beats = BeatTracker::default().track_envelope(&envelope, params, bpm)?;
+        self.estimate(&envelope, &beats)
+    }
+
+    pub fn run(
+        &self,
+        context: &AnalysisContext,
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<MeterEstimate> {
+        let envelope = context.envelope()?;
+        let beats = BeatTracker::default().track_envelope(&envelope, params, bpm)?;
+        self.estimate(&envelope, &beats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::*;
+
+    #[test]
+    fn waltz() {
+        let analyser = MeterAnalyser::default();
+        let params = EstimatorParams::default();
+        let waltz = analyser
+            .analyse(
+                &render(&accented_clicks(150.0, 3, 30.0), 30.0),
+                &params,
+                Some(150.0),
+            )
+            .unwrap();
+        assert_eq!(waltz.meter, MeterE::Triple);
+        let swing = analyser
+            .analyse(
+                &render(&accented_clicks(150.0, 4, 30.0), 30.0),
+                &params,
+                Some(150.0),
+            )
+            .unwrap();
+        assert_eq!(swing.meter, MeterE::Duple);
+    }
+}
@@ -0,0 +1,8 @@
+/*
+    Analysers, that extract information other than a single tempo from a track.
+*/
+pub mod beats;
+pub mod curve;
+pub mod meter;
+pub mod properties;
+pub mod swing;
@@ -0,0 +1,374 @@
+/*
+    Audio properties of a track, for set planning: its duration, format, and
+    how loud it is. The format (sample rate, channels, bitrate) comes from
+    ffprobe, and loudness from ffmpeg's ebur128 filter, when they're
+    installed. Loudness is integrated loudness per EBU R128 (ITU-R BS.1770),
+    which sums all of the channels of a track, along with the sample peak.
+    Without ffmpeg, we can only measure the (mono) samples that we decode,
+    which gives the loudness of mono tracks alone.
+*/
+use estimators::context::AnalysisContext;
+use estimators::SAMPLE_RATE;
+use serde_json::{self, Value};
+use shelltools::ffmpeg::FfmpegLoudnessCommand;
+use shelltools::ffprobe::FfprobeCommand;
+use shelltools::generic::ShellProgram;
+use std::f64::consts::PI;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AudioProperties {
+    pub duration: Option<f64>,    // seconds
+    pub sample_rate: Option<u32>, // of the file, in Hz
+    pub channels: Option<u32>,
+    pub bitrate: Option<u32>,  // bits per second
+    pub loudness: Option<f64>, // integrated loudness, LUFS
+    pub peak: Option<f64>,     // sample peak, dBFS
+}
+
+impl AudioProperties {
+    // Format the properties as tab separated values, for exporting
+    pub fn format(&self) -> String {
+        fn value<T: ToString>(v: Option<T>) -> String {
+            v.map_or("NA".to_string(), |v| v.to_string())
+        }
+        [
+            value(self.duration.map(|d| format!("{:.2}", d))),
+            value(self.sample_rate),
+            value(self.channels),
+            value(self.bitrate),
+            value(self.loudness.map(|l| format!("{:.1}", l))),
+            value(self.peak.map(|p| format!("{:.1}", p))),
+        ]
+        .join("\t")
+    }
+}
+
+// A second order IIR filter section
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+}
+
+impl Biquad {
+    fn filter(&self, input: &[f64]) -> Vec<f64> {
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        input
+            .iter()
+            .map(|&x| {
+                let y = self.b[0] * x + self.b[1] * x1 + self.b[2] * x2
+                    - self.a[1] * y1
+                    - self.a[2] * y2;
+                x2 = x1;
+                x1 = x;
+                y2 = y1;
+                y1 = y;
+                y
+            })
+            .collect()
+    }
+}
+
+// The K-weighting filter of BS.1770 - a high shelf (modelling the head),
+// followed by a high pass. The standard gives coefficients at 48KHz, so we
+// derive them for any rate from the analogue prototypes, as libebur128 does.
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, gain, q) = (
+        1_681.974_450_955_533,
+        3.999_843_853_973_347,
+        0.707_175_236_955_419_6,
+    );
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+
+    let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    };
+    [shelf, high_pass]
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+// The integrated loudness of (mono) samples, in LUFS. This averages the power
+// of overlapping 400ms blocks, ignoring silent blocks (below -70 LUFS), and
+// then blocks more than 10 LU below the loudness of the rest.
+pub fn integrated_loudness(samples: &[f32], rate: f64) -> Option<f64> {
+    let [shelf, high_pass] = k_weighting(rate);
+    let input: Vec<f64> = samples.iter().map(|&s| f64::from(s)).collect();
+    let weighted = high_pass.filter(&shelf.filter(&input));
+
+    let block = (0.4 * rate) as usize;
+    let hop = block / 4;
+    if block == 0 || weighted.len() < block {
+        return None;
+    }
+    let powers: Vec<f64> = (0..=(weighted.len() - block) / hop)
+        .map(|i| {
+            let window = &weighted[i * hop..i * hop + block];
+            window.iter().map(|s| s * s).sum::<f64>() / block as f64
+        })
+        .collect();
+
+    let gated = |threshold: f64| -> Option<f64> {
+        let loud: Vec<f64> = powers
+            .iter()
+            .cloned()
+            .filter(|&p| p > 0.0 && lufs(p) > threshold)
+            .collect();
+        if loud.is_empty() {
+            None
+        } else {
+            Some(loud.iter().sum::<f64>() / loud.len() as f64)
+        }
+    };
+    let relative = lufs(gated(-70.0)?) - 10.0;
+    gated(relative).map(lufs)
+}
+
+// The largest absolute sample value, in dBFS
+pub fn sample_peak(samples: &[f32]) -> Option<f64> {
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    if peak > 0.0 {
+        Some(20.0 * f64::from(peak).log10())
+    } else {
+        None
+    }
+}
+
+// Read a number from a field of ffprobe's output, which (depending on the
+// field) may be written as a number, or as a string.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+// Read the properties of a file from ffprobe's (JSON) description of it
+pub fn parse_probe(json: &str) -> Option<AudioProperties> {
+    let probe: Value = serde_json::from_str(json).ok()?;
+    let format = &probe["format"];
+    let stream = &probe["streams"][0];
+    Some(AudioProperties {
+        duration: number(&format["duration"]).or_else(|| number(&stream["duration"])),
+        sample_rate: number(&stream["sample_rate"]).map(|r| r as u32),
+        channels: number(&stream["channels"]).map(|c| c as u32),
+        bitrate: number(&stream["bit_rate"])
+            .or_else(|| number(&format["bit_rate"]))
+            .map(|b| b as u32),
+        loudness: None,
+        peak: None,
+    })
+}
+
+// Ask ffprobe for the format of a file
+pub fn probe(audio_file: &Path) -> Option<AudioProperties> {
+    if audio_file.to_str().is_none() {
+        error!(
+            "Cannot probe {:?}, as its path is not valid unicode",
+            audio_file
+        );
+        return None;
+    }
+    let (stdout, _) = FfprobeCommand::new(&audio_file.to_path_buf()).run()?;
+    parse_probe(&stdout).or_else(|| {
+        error!("Could not parse ffprobe output for {:?}", audio_file);
+        None
+    })
+}
+
+// Read the loudness of a file from the summary of ffmpeg's ebur128 filter:
+//
+//   Integrated loudness:
+//     I:         -14.3 LUFS
+//     ...
+//   Sample peak:
+//     Peak:        0.3 dBFS
+pub fn parse_ebur128(stderr: &str) -> Option<AudioProperties> {
+    let summary = &stderr[stderr.rfind("Summary:")?..];
+    let field = |name: &str| -> Option<f64> {
+        let line = summary
+            .lines()
+            .map(str::trim)
+            .find(|l| l.starts_with(name))?;
+        line[name.len()..].split_whitespace().next()?.parse().ok()
+    };
+    Some(AudioProperties {
+        // silence is reported as -70 LUFS, with a peak of -inf dBFS
+        loudness: field("I:").filter(|l| *l > -70.0),
+        peak: field("Peak:").filter(|p| p.is_finite()),
+        ..AudioProperties::default()
+    })
+}
+
+// Measure the loudness of a file, with ffmpeg
+pub fn meter(audio_file: &Path) -> Option<AudioProperties> {
+    if audio_file.to_str().is_none() {
+        error!(
+            "Cannot measure {:?}, as its path is not valid unicode",
+            audio_file
+        );
+        return None;
+    }
+    let (_, stderr) = FfmpegLoudnessCommand::new(&audio_file.to_path_buf()).run()?;
+    parse_ebur128(&stderr).or_else(|| {
+        error!(
+            "Could not parse ffmpeg loudness summary for {:?}",
+            audio_file
+        );
+        None
+    })
+}
+
+pub struct PropertiesAnalyser {}
+
+impl PropertiesAnalyser {
+    // Measure the duration and loudness of (mono) samples
+    pub fn measure(&self, samples: &[f32]) -> AudioProperties {
+        AudioProperties {
+            duration: Some(samples.len() as f64 / SAMPLE_RATE),
+            loudness: integrated_loudness(samples, SAMPLE_RATE),
+            peak: sample_peak(samples),
+            ..AudioProperties::default()
+        }
+    }
+
+    // Find the properties of a track. We only decode it when ffprobe or
+    // ffmpeg can't tell us what we want, and then over whatever the context
+    // decodes, so it should decode all of the track.
+    pub fn run(&self, context: &AnalysisContext) -> Option<AudioProperties> {
+        let probed = probe(&context.audio_file);
+        let metered = meter(&context.audio_file);
+        let duration = probed.as_ref().and_then(|p| p.duration);
+        let measured = if duration.is_some() && metered.is_some() {
+            None
+        } else {
+            context.samples().map(|samples| self.measure(&samples))
+        };
+        if probed.is_none() && metered.is_none() && measured.is_none() {
+            return None;
+        }
+
+        let probed = probed.unwrap_or_default();
+        let measured = measured.unwrap_or_default();
+        // what we decode is a mono mix, so only has the loudness of mono tracks
+        let level = match metered {
+            Some(metered) => metered,
+            None if probed.channels == Some(1) => measured.clone(),
+            None => {
+                info!(
+                    "Not measuring the loudness of {:?} without ffmpeg, as it may not be mono",
+                    context.audio_file
+                );
+                AudioProperties::default()
+            }
+        };
+        Some(AudioProperties {
+            duration: probed.duration.or(measured.duration),
+            loudness: level.loudness,
+            peak: level.peak,
+            ..probed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, seconds: f64) -> Vec<f32> {
+        (0..(seconds * SAMPLE_RATE) as usize)
+            .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / SAMPLE_RATE).sin()) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn loudness() {
+        // a 1KHz sine at -20dBFS, in a single channel, is -23 LUFS
+        let tone = sine(1000.0, 0.1, 10.0);
+        let loudness = integrated_loudness(&tone, SAMPLE_RATE).unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "Loudness {}", loudness);
+        assert!((sample_peak(&tone).unwrap() + 20.0).abs() < 0.01);
+
+        // quiet passages are gated out
+        let mut quieter = tone.clone();
+        quieter.extend(sine(1000.0, 0.001, 10.0));
+        let gated = integrated_loudness(&quieter, SAMPLE_RATE).unwrap();
+        assert!((gated - loudness).abs() < 0.1, "Gated loudness {}", gated);
+
+        assert!(integrated_loudness(&[0.0; 44100], SAMPLE_RATE).is_none());
+        assert!(sample_peak(&[0.0; 10]).is_none());
+    }
+
+    #[test]
+    fn measure() {
+        let analyser = PropertiesAnalyser {};
+        let measured = analyser.measure(&sine(1000.0, 0.1, 10.0));
+        assert_eq!(measured.duration, Some(10.0));
+        assert!((measured.loudness.unwrap() + 23.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn ebur128() {
+        let stderr =
+            "[Parsed_ebur128_0 @ 0x55d1c3c0] t: 215.1  TARGET:-23 LUFS    M: -13.9 S: -14.1
+[Parsed_ebur128_0 @ 0x55d1c3c0] Summary:
+
+  Integrated loudness:
+    I:         -14.3 LUFS
+    Threshold: -24.6 LUFS
+
+  Loudness range:
+    LRA:         5.4 LU
+    Threshold:  -34.6 LUFS
+    LRA low:    -18.6 LUFS
+    LRA high:   -13.2 LUFS
+
+  Sample peak:
+    Peak:        0.3 dBFS
+";
+        let properties = parse_ebur128(stderr).unwrap();
+        assert_eq!(properties.loudness, Some(-14.3));
+        assert_eq!(properties.peak, Some(0.3));
+
+        let silence = "Summary:\n  Integrated loudness:\n    I:  -70.0 LUFS\n  Sample peak:\n    Peak:  -inf dBFS\n";
+        let properties = parse_ebur128(silence).unwrap();
+        assert_eq!((properties.loudness, properties.peak), (None, None));
+        assert!(parse_ebur128("ffmpeg: command not found").is_none());
+    }
+
+    #[test]
+    fn ffprobe() {
+        let json = r#"{
+            "streams": [{"codec_name": "mp3", "sample_rate": "44100", "channels": 2, "bit_rate": "320000"}],
+            "format": {"duration": "215.146000", "bit_rate": "321234"}
+        }"#;
+        let properties = parse_probe(json).unwrap();
+        assert_eq!(properties.duration, Some(215.146));
+        assert_eq!(properties.sample_rate, Some(44100));
+        assert_eq!(properties.channels, Some(2));
+        assert_eq!(properties.bitrate, Some(320000));
+        assert_eq!(
+            properties.format(),
+            "215.15\t44100\t2\t320000\tNA\tNA".to_string()
+        );
+        assert!(parse_probe("not json").is_none());
+    }
+}
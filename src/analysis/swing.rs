@@ -0,0 +1,135 @@
+/*
+    Swing ratio estimation. Swung eighth notes divide each beat into a long
+    and a short note - the swing ratio is the ratio of their lengths, from
+    1:1 for straight eighths, through 2:1 for a triplet feel, to 3:1 for a
+    hard (dotted eighth/sixteenth) swing.
+
+    We estimate it by folding the onset envelope onto the beat grid, to get
+    the average onset strength at each phase of a beat, and then finding
+    where the offbeat eighth note falls within the beat.
+*/
+use analysis::beats::*;
+use dsp::onset::OnsetEnvelope;
+use dsp::peaks;
+use estimators::context::AnalysisContext;
+use estimators::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SwingRatio {
+    pub ratio: f64,    // the ratio of the long to the short eighth note
+    pub strength: f64, // the strength of the offbeat, relative to the beat
+}
+
+pub struct SwingAnalyser {
+    pub resolution: usize, // the number of phases into which each beat is divided
+}
+
+impl Default for SwingAnalyser {
+    fn default() -> SwingAnalyser {
+        SwingAnalyser { resolution: 48 }
+    }
+}
+
+impl SwingAnalyser {
+    // The phases of the beat at which we look for the offbeat, which cover
+    // everything from (just before) straight eighths to a 4:1 swing.
+    const EARLIEST: f64 = 0.45;
+    const LATEST: f64 = 0.8;
+
+    // The average onset strength at each phase of the beats
+    fn profile(&self, envelope: &OnsetEnvelope, beats: &Beats) -> Vec<f64> {
+        let mut profile = vec![0.0; self.resolution + 1];
+        let value = |frame: f64| {
+            let i = frame.floor().max(0.0) as usize;
+            match (envelope.values.get(i), envelope.values.get(i + 1)) {
+                (Some(a), Some(b)) => a + (b - a) * (frame - i as f64),
+                (Some(a), None) => *a,
+                _ => 0.0,
+            }
+        };
+        for pair in beats.times.windows(2) {
+            let (start, end) = (envelope.frame(pair[0]), envelope.frame(pair[1]));
+            for (phase, p) in profile.iter_mut().enumerate() {
+                *p += value(start + (end - start) * phase as f64 / self.resolution as f64);
+            }
+        }
+        profile
+    }
+
+    // Estimate the swing ratio from the onsets between tracked beats
+    #[flame("SwingAnalyser")]
+    pub fn estimate(&self, envelope: &OnsetEnvelope, beats: &Beats) -> Option<SwingRatio> {
+        if beats.times.len() < 2 {
+            return None;
+        }
+        let profile = self.profile(envelope, beats);
+        debug!("Beat profile: {:?}", profile);
+
+        let (from, to) = (
+            (Self::EARLIEST * self.resolution as f64).floor() as usize,
+            (Self::LATEST * self.resolution as f64).ceil() as usize,
+        );
+        let (position, value) = peaks(&profile, from, to + 1).into_iter().fold(
+            None,
+            |best: Option<(f64, f64)>, next| match best {
+                Some(b) if b.1 >= next.1 => Some(b),
+                _ => Some(next),
+            },
+        )?;
+
+        let phase = position / self.resolution as f64;
+        let beat = profile[0].max(profile[self.resolution]);
+        Some(SwingRatio {
+            ratio: phase / (1.0 - phase),
+            strength: if beat > 0.0 { value / beat } else { 0.0 },
+        })
+    }
+
+    // Estimate the swing ratio of a (mono) stream of samples, tracking the
+    // beats at the given tempo, or an estimated one if we don't know it.
+    pub fn analyse(
+        &self,
+        samples: &[f32],
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<SwingRatio> {
+        let envelope = OnsetEnvelope::spectral_flux(samples, SAMPLE_RATE);
+        let beats = BeatTracker::default().track_envelope(&envelope, params, bpm)?;
+        self.estimate(&envelope, &beats)
+    }
+
+    // Estimate the swing ratio of a track, from its (shared) onset envelope
+    pub fn run(
+        &self,
+        context: &AnalysisContext,
+        params: &EstimatorParams,
+        bpm: Option<f64>,
+    ) -> Option<SwingRatio> {
+        let envelope = context.envelope()?;
+        let beats = BeatTracker::default().track_envelope(&envelope, params, bpm)?;
+        self.estimate(&envelope, &beats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::*;
+
+    #[test]
+    fn ratios() {
+        let analyser = SwingAnalyser::default();
+        for ratio in [1.0, 2.0, 3.0].iter() {
+            let samples = render(&swung_clicks(140.0, *ratio, 20.0), 20.0);
+            let swing = analyser
+                .analyse(&samples, &EstimatorParams::default(), Some(140.0))
+                .unwrap();
+            assert!(
+                (swing.ratio - ratio).abs() < 0.25,
+                "Estimated a swing ratio of {} for {}",
+                swing.ratio,
+                ratio
+            );
+        }
+    }
+}
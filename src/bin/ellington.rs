@@ -3,6 +3,7 @@
 */
 use std::fs;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 #[macro_use]
 extern crate log;
@@ -24,14 +25,21 @@ extern crate flamer;
 
 extern crate ellington;
 
-use ellington::library::ellingtondata::EllingtonData;
+use ellington::analysis::curve::*;
+use ellington::analysis::meter::MeterAnalyser;
+use ellington::analysis::swing::SwingAnalyser;
+use ellington::library::ellingtondata::*;
 use ellington::library::trackmetadata::*;
 use ellington::library::Entry;
 use ellington::library::Library;
 
-use ellington::estimators::BellsonTempoEstimator;
-use ellington::estimators::FfmpegNaiveTempoEstimator;
-use ellington::estimators::TempoEstimator;
+use ellington::estimators::command;
+use ellington::estimators::context::AnalysisContext;
+use ellington::estimators::ensemble::*;
+use ellington::estimators::octave::SwingPrior;
+use ellington::estimators::registry;
+use ellington::estimators::registry::EstimatorFactory;
+use ellington::estimators::EstimatorParams;
 
 use ellington::types::*;
 
@@ -137,6 +145,10 @@ fn dump(matches: &ArgMatches) -> () {
     let data: char = match matches.value_of("value").unwrap() {
         "location" => 'l',
         "title" => 't',
+        "beats" => 'b',
+        "swing" => 's',
+        "meter" => 'm',
+        "properties" => 'p',
         _ => panic!("We should always get a value, this should not happen!"),
     };
 
@@ -145,38 +157,184 @@ fn dump(matches: &ArgMatches) -> () {
             println!("{}", track.location.to_str().unwrap());
         } else if data == 't' {
             println!("{}", track.metadata.unwrap().name);
+        } else if data == 'b' {
+            match track.beats {
+                Some(beats) => println!("{}\t{}", track.location.to_str().unwrap(), beats.format()),
+                None => info!("No beats stored for track {:?}", track.location),
+            }
+        } else if data == 's' {
+            match track.eldata.swing {
+                Some(swing) => println!("{}\t{:.3}", track.location.to_str().unwrap(), swing.ratio),
+                None => info!("No swing ratio stored for track {:?}", track.location),
+            }
+        } else if data == 'm' {
+            match track.eldata.meter {
+                Some(meter) => println!("{}\t{}", track.location.to_str().unwrap(), meter),
+                None => info!("No meter stored for track {:?}", track.location),
+            }
+        } else if data == 'p' {
+            match track.properties {
+                Some(properties) => println!(
+                    "{}\t{}",
+                    track.location.to_str().unwrap(),
+                    properties.format()
+                ),
+                None => info!("No properties stored for track {:?}", track.location),
+            }
         }
     }
 }
 
+#[flame]
+fn beats(matches: &ArgMatches) {
+    let library_file = PathBuf::from(matches.value_of("LIBRARY").unwrap());
+    info!("Reading from library: {:?}", library_file);
+    let mut lib: Library = Library::read_from_file(&library_file)
+        .unwrap_or_else(|| panic!("Failed to read ellington library {:?}!", library_file));
+
+    let params = estimator_params(matches);
+    let force = matches.is_present("force");
+    lib.run_beat_analysis(
+        &params,
+        force,
+        matches.is_present("meter"),
+        matches.is_present("swing"),
+    );
+
+    lib.write_to_file(&library_file);
+}
+
+#[flame]
+fn properties(matches: &ArgMatches) {
+    let library_file = PathBuf::from(matches.value_of("LIBRARY").unwrap());
+    info!("Reading from library: {:?}", library_file);
+    let mut lib: Library = Library::read_from_file(&library_file)
+        .unwrap_or_else(|| panic!("Failed to read ellington library {:?}!", library_file));
+
+    lib.run_property_analysis(matches.is_present("force"));
+
+    lib.write_to_file(&library_file);
+}
+
+// Exit with a usage error about the value of an argument.
+fn invalid_value(description: &str) -> ! {
+    clap::Error::with_description(description, clap::ErrorKind::InvalidValue).exit()
+}
+
+// Parse a value passed on the command line, if it was given.
+fn parse_value<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|v| {
+        v.parse::<T>().unwrap_or_else(|_| {
+            let flag = name.replace('_', "-");
+            invalid_value(&format!("Could not parse value '{}' for '--{}'", v, flag))
+        })
+    })
+}
+
+// Read the estimator parameters passed on the command line.
+fn estimator_params(matches: &ArgMatches) -> EstimatorParams {
+    let defaults = EstimatorParams::default();
+    let min_bpm = parse_value(matches, "min_bpm").unwrap_or(defaults.min_bpm);
+    let max_bpm = parse_value(matches, "max_bpm").unwrap_or(defaults.max_bpm);
+
+    let octave_correction = if matches.occurrences_of("octave_correction") > 0 {
+        let prior = SwingPrior::default();
+        Some(SwingPrior {
+            centre_bpm: parse_value(matches, "prior_centre").unwrap_or(prior.centre_bpm),
+            width: parse_value(matches, "prior_width").unwrap_or(prior.width),
+            min_bpm,
+            max_bpm,
+        })
+    } else {
+        None
+    };
+
+    let params = EstimatorParams {
+        min_bpm,
+        max_bpm,
+        accuracy: parse_value(matches, "accuracy").unwrap_or(defaults.accuracy),
+        analysis_length: parse_value(matches, "analysis_length"),
+        octave_correction,
+        meter: None,
+        timeout: parse_value(matches, "timeout"),
+    };
+    if !params.valid() {
+        invalid_value(&format!(
+            "The tempo range must have 0 < '--min-bpm' < '--max-bpm', but was {} to {}",
+            min_bpm, max_bpm
+        ));
+    }
+    params
+}
+
+// Read the weights for the ensemble estimator passed on the command line,
+// as a list of comma separated 'algorithm=weight' pairs.
+fn ensemble_params(matches: &ArgMatches) -> EnsembleParams {
+    let mut params = EnsembleParams::default();
+    if let Some(weights) = matches.value_of("ensemble_weights") {
+        for pair in weights.split(',') {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next().map(|w| w.trim().parse::<f64>())) {
+                (Some(algorithm), Some(Ok(weight))) => {
+                    params
+                        .weights
+                        .insert(AlgorithmE::parse(algorithm.trim()), weight);
+                }
+                _ => panic!("Could not parse ensemble weight '{}'!", pair),
+            }
+        }
+    }
+    params
+}
+
+// Read the tempo curve parameters passed on the command line.
+fn curve_params(matches: &ArgMatches) -> CurveParams {
+    let defaults = CurveParams::default();
+    CurveParams {
+        window: parse_value(matches, "curve_window").unwrap_or(defaults.window),
+        hop: parse_value(matches, "curve_hop").unwrap_or(defaults.hop),
+    }
+}
+
 #[flame]
 fn query_estimator(
     algorithm: AlgorithmE,
-    caches: &Vec<EllingtonData>,
+    cache: &EllingtonData,
     force: bool,
     never: bool,
-    f: impl Fn() -> Option<i64>,
-) -> BpmE {
+    current: Option<&Provenance>,
+    f: impl Fn() -> Option<TempoEstimate>,
+) -> (BpmE, Option<TempoEstimate>) {
     info!("Querying estimator '{}'", algorithm.print());
 
+    fn run(f: impl Fn() -> Option<TempoEstimate>) -> (BpmE, Option<TempoEstimate>) {
+        match f() {
+            Some(estimate) => (estimate.to_bpm(), Some(estimate)),
+            None => (BpmE::NA, None),
+        }
+    }
+
     // Force will never conflict with never, so we don't need to check it as well
     if force {
-        return BpmE::from_option(f());
+        return run(f);
     }
-    // Run through the caches to search for the algorithm
-    for cache in caches {
-        match cache.algs.get(&algorithm) {
-            Some(BpmE::NA) => info!("NA found in cache, ignoring"),
-            Some(tmpo) => return tmpo.clone(),
-            _ => info!("Algorithm not in cache"),
+    // Search the cache for the algorithm
+    let estimate = cache.estimates.get(&algorithm);
+    match (cache.algs.get(&algorithm), current) {
+        (Some(BpmE::NA), _) => info!("NA found in cache, ignoring"),
+        // If we're refreshing stale results, ignore any not made by the current estimator.
+        (Some(_), Some(p)) if estimate.filter(|e| e.is_current(p)).is_none() => {
+            info!("Stale result found in cache, ignoring")
         }
+        (Some(tmpo), _) => return (tmpo.clone(), estimate.cloned()),
+        _ => info!("Algorithm not in cache"),
     }
 
     // If it's not found, run the estimator, so long as 'never' has not been specified.
     if never {
-        BpmE::NA
+        (BpmE::NA, None)
     } else {
-        BpmE::from_option(f())
+        run(f)
     }
 }
 
@@ -254,24 +412,48 @@ fn query(matches: &ArgMatches) -> () {
     let _library_trackdata: Option<TrackMetadata> =
         library_entry.clone().and_then(|e| e.metadata.clone());
 
+    // Get the (cached) tempo curve from that entry
+    let library_curve: Option<TempoCurve> = library_entry.as_ref().and_then(|e| e.curve.clone());
+
     // Get the ellington data from that entry
     let library_eldata: EllingtonData = library_entry
         .and_then(|e| Some(e.eldata.clone()))
         .unwrap_or(EllingtonData::empty());
 
+    // How to configure the estimators (and analysers) when they're run.
+    let mut params: EstimatorParams = estimator_params(matches);
+    info!("Using estimator parameters: {:?}", params);
+
+    // Everything that we run over the track shares its tags, decoded audio,
+    // and onset envelope, so that the file is read (at most) once.
+    let context = AnalysisContext::new(&audio_path, &params);
+
     // Load the track data from the audio file
-    let track_metadata: Option<TrackMetadata> = TrackMetadata::from_file(audio_path.as_path());
+    let track_metadata: Option<TrackMetadata> = context.metadata();
 
-    // get data from the comments
+    // get data from the comments, and the title, reporting any that's malformed
+    let mode = if matches.is_present("strict") {
+        ParseMode::Strict
+    } else {
+        ParseMode::Lenient
+    };
+    let read = |source: &str, (ed, errors): (EllingtonData, Vec<ParseError>)| {
+        for e in errors {
+            warn!(
+                "Malformed ellington data in {} of {:?}, {}",
+                source, audio_file, e
+            );
+        }
+        ed
+    };
     let comment_eldata: EllingtonData = track_metadata
         .as_ref()
-        .and_then(|tm| Some(tm.comment_metadata()))
+        .map(|tm| read("comments", tm.comment_metadata(mode)))
         .unwrap_or(EllingtonData::empty());
 
-    // get data from the title
     let title_eldata: EllingtonData = track_metadata
         .as_ref()
-        .and_then(|tm| Some(tm.title_metadata()))
+        .map(|tm| read("title", tm.title_metadata(mode)))
         .unwrap_or(EllingtonData::empty());
 
     info!("Library metadata: {:?}", library_eldata);
@@ -295,6 +477,9 @@ fn query(matches: &ArgMatches) -> () {
     // Or if we're not allowed to run them!
     let never: bool = matches.occurrences_of("never") > 0;
 
+    // Or if we should only run them when their cached results are out of date.
+    let refresh_stale: bool = matches.occurrences_of("refresh_stale") > 0;
+
     /*
         4. Start iterating over estimators.
     */
@@ -308,39 +493,165 @@ fn query(matches: &ArgMatches) -> () {
         "userdata" => vec![library_eldata],
         _ => panic!("We should always get a priority, this should not happen!"),
     };
+    // Merge them into a single cache, with the preferred source on the left and
+    // the others, in order, on the right, so that the merge policy decides
+    // what happens when sources disagree.
+    let policy = MergePolicy::parse(matches.value_of("merge_policy").unwrap())
+        .expect("We should always get a merge policy, this should not happen!");
+    let cache: EllingtonData = EllingtonData::merge_all(caches, policy);
+    info!("Merged cache ({:?}): {:?}", policy, cache);
 
     let mut ed = EllingtonData::empty();
 
-    // Start with the "actual" value
-    // TODO: Should be able to read this from the library as well!
-    if estimator == AlgorithmE::Actual.print() || estimator == "all" {
-        info!("Running estimator {}", AlgorithmE::Actual.print());
-        let tempo = query_estimator(AlgorithmE::Actual, &caches, force, never, || {
-            TrackMetadata::from_file(audio_path.as_path()).and_then(|tmd| tmd.bpm)
-        });
-        ed.algs.insert(AlgorithmE::Actual, tempo);
+    // Work out which estimators have been requested, by consulting the registry.
+    // The ensemble is not a registered estimator, as it runs on the results of the others.
+    let registry = registry::registry();
+    let names: Vec<&str> = if estimator == "all" {
+        registry::DEFAULT_ESTIMATORS.to_vec()
+    } else {
+        estimator.split(',').map(|name| name.trim()).collect()
+    };
+    let mut ensemble = false;
+    let factories: Vec<Arc<dyn EstimatorFactory>> = names
+        .into_iter()
+        .filter(|name| {
+            if *name == AlgorithmE::Ensemble.print() {
+                ensemble = true;
+                false
+            } else {
+                true
+            }
+        })
+        .map(|name| {
+            registry
+                .lookup(name)
+                .expect("We always check the estimators first, this should not happen!")
+        })
+        .collect();
+
+    // Work out the meter of the track, either from the command line, the cache, or
+    // by detecting it, so that the estimators can avoid errors specific to the meter.
+    ed.meter = match matches.value_of("meter") {
+        Some("detect") => match cache.meter {
+            Some(meter) if !force => Some(meter),
+            _ if never => None,
+            _ => {
+                let bpm = cache.known_bpm();
+                MeterAnalyser::default()
+                    .run(&context, &params, bpm)
+                    .map(|estimate| {
+                        info!("Detected meter: {:?}", estimate);
+                        estimate.meter
+                    })
+            }
+        },
+        Some(meter) => MeterE::parse(meter),
+        None => cache.meter,
+    };
+    params.meter = ed.meter;
+
+    for factory in factories {
+        info!("Running estimator {}", factory.name());
+        let current = if refresh_stale {
+            Some(factory.provenance(&params))
+        } else {
+            None
+        };
+        let (tempo, estimate) = query_estimator(
+            factory.algorithm(),
+            &cache,
+            force,
+            never,
+            current.as_ref(),
+            || factory.estimate(&params, &context),
+        );
+        info!("Got result {:?} ({:?}) from estimator.", tempo, estimate);
+        // keep what we know about tempos from the cache (e.g. that they've been verified)
+        let algorithm = factory.algorithm();
+        if cache.algs.get(&algorithm) == Some(&tempo)
+            && cache.estimates.get(&algorithm) == estimate.as_ref()
+        {
+            if let Some(attributes) = cache.attributes.get(&algorithm) {
+                ed.attributes.insert(algorithm.clone(), attributes.clone());
+            }
+        }
+        ed.algs.insert(algorithm.clone(), tempo);
+        if let Some(e) = estimate {
+            ed.estimates.insert(algorithm, e);
+        }
     }
 
-    // Run bellson, and try to add the result.
-    if estimator == AlgorithmE::Bellson.print() || estimator == "all" {
-        info!("Running estimator {}", AlgorithmE::Bellson.print());
-        let tempo = query_estimator(AlgorithmE::Bellson, &caches, force, never, || {
-            BellsonTempoEstimator::run(&audio_path)
-        });
-        info!("Got result {:?} from estimator.", tempo);
-        ed.algs.insert(BellsonTempoEstimator::ALGORITHM, tempo);
+    // Combine everything that we know about the track into an ensemble tempo, using
+    // the results that we've just calculated in preference to those in the cache.
+    if ensemble {
+        info!("Running ensemble estimator");
+        let mut available = cache.clone() + ed.clone();
+        match available.insert_ensemble(&EnsembleEstimator::new(&ensemble_params(matches))) {
+            Some(()) => {
+                ed.insert_estimate(
+                    AlgorithmE::Ensemble,
+                    available.estimates[&AlgorithmE::Ensemble].clone(),
+                );
+                ed.ensemble = available.ensemble;
+            }
+            None => {
+                info!("No tempos available to combine into an ensemble");
+                ed.algs.insert(AlgorithmE::Ensemble, BpmE::NA);
+            }
+        }
     }
 
-    // Run the naive estimator
-    if estimator == AlgorithmE::Naive.print() || estimator == "all" {
-        info!("Running estimator {}", AlgorithmE::Naive.print());
-        let tempo = query_estimator(AlgorithmE::Naive, &caches, force, never, || {
-            FfmpegNaiveTempoEstimator::run(&audio_path)
-        });
-        info!("Got result {:?} from estimator.", tempo);
-        ed.algs.insert(FfmpegNaiveTempoEstimator::ALGORITHM, tempo);
+    // Estimate how hard the track swings, if it's been asked for, at the best
+    // tempo that we know for it.
+    if matches.occurrences_of("swing") > 0 {
+        ed.swing = match cache.swing.clone() {
+            Some(swing) if !force => {
+                info!("Using cached swing ratio");
+                Some(swing)
+            }
+            _ if never => None,
+            _ => {
+                let bpm = ed.known_bpm().or_else(|| cache.known_bpm());
+                SwingAnalyser::default().run(&context, &params, bpm)
+            }
+        };
     }
 
+    // Calculate the tempo curve of the track, if it's been asked for, and we
+    // don't have an up to date one in the library.
+    let curve: Option<TempoCurve> = matches.value_of("curve").and_then(|name| {
+        let factory = registry.lookup(name).unwrap_or_else(|| {
+            panic!(
+                "Estimator '{}' is not registered! Registered estimators: {}",
+                name,
+                registry.names().join(", ")
+            )
+        });
+        let cparams = curve_params(matches);
+        match library_curve {
+            Some(ref c) if !force && c.algorithm == factory.algorithm() && c.params == cparams => {
+                info!("Using cached tempo curve");
+                return library_curve.clone();
+            }
+            _ if never => return None,
+            _ => info!("Calculating tempo curve with estimator {}", factory.name()),
+        }
+        let samples = context.samples()?;
+        TempoCurve::analyse(
+            &*factory.build(&params),
+            factory.algorithm(),
+            &samples,
+            &cparams,
+        )
+        .or_else(|| {
+            error!(
+                "Could not calculate a tempo curve with estimator {}",
+                factory.name()
+            );
+            None
+        })
+    });
+
     /*
         5 - Write to the library if --pure is not specified
     */
@@ -353,6 +664,9 @@ fn query(matches: &ArgMatches) -> () {
             .unwrap();
 
         new_library.update(&PathBuf::from(audio_file), ed.clone());
+        if let Some(c) = &curve {
+            new_library.update_curve(&PathBuf::from(audio_file), c.clone());
+        }
 
         // Write the computed library to the file
         new_library.write_to_file(&PathBuf::from(library_file));
@@ -369,20 +683,35 @@ fn query(matches: &ArgMatches) -> () {
     match matches.value_of("output") {
         Some("update") => {
             info!("Updating metadata passed in.");
-            let minimal = matches.occurrences_of("minimal") > 0;
+            // The meter is only written to v2 data, so we write v2 if a meter was
+            // asked for, unless a version was given explicitly.
+            let version =
+                if matches.occurrences_of("ed_version") == 0 && matches.is_present("meter") {
+                    FormatVersion::V2
+                } else {
+                    FormatVersion::parse(matches.value_of("ed_version").unwrap()).unwrap()
+                };
+            if version == FormatVersion::V1 && ed.meter.is_some() {
+                warn!("Not writing the meter of the track, as it needs version 2 ellington data");
+            }
+            let options = FormatOptions {
+                minimal: matches.occurrences_of("minimal") > 0,
+                precision: parse_value(matches, "precision"),
+                version,
+            };
             let modification = UpdateBehaviour::parse(matches.value_of("modification").unwrap());
 
             match matches.value_of("metadata") {
                 Some("none") => {
                     // If none - just print the formatted output
-                    println!("{}", ed.format(minimal).unwrap())
+                    println!("{}", ed.format_with(&options).unwrap())
                 }
                 Some("title") => {
                     // If title, update the title
                     info!("Updating title data.");
                     let trmeta = track_metadata
                         .unwrap_or_else(|| panic!("No metadata found for track, failing!"));
-                    match ed.update_data(&trmeta.name, modification, minimal) {
+                    match ed.update_data(&trmeta.name, modification, &options) {
                         Ok(s) => println!("{}", s),
                         Err(e) => panic!("Could not update metadata in string! Error: {:?}", e),
                     }
@@ -392,21 +721,21 @@ fn query(matches: &ArgMatches) -> () {
                     let trmeta = track_metadata
                         .unwrap_or_else(|| panic!("No metadata found for track, failing!"));
                     match trmeta.comments {
-                        Some(v) => match ed.update_data(&v[0], modification, minimal) {
+                        Some(v) => match ed.update_data(&v[0], modification, &options) {
                             Ok(s) => println!("{}", s),
                             Err(e) => panic!("Could not update metadata in string! Error: {:?}", e),
                         },
-                        _ => println!("{}", ed.format(minimal).unwrap()),
+                        _ => println!("{}", ed.format_with(&options).unwrap()),
                     }
                 }
                 Some("userdata") => {
                     // Read the userdata that we have been passed.
                     match matches.value_of("userdata") {
-                        Some(u) => match ed.update_data(&String::from(u), modification, minimal) {
+                        Some(u) => match ed.update_data(&String::from(u), modification, &options) {
                             Ok(s) => println!("{}", s),
                             Err(e) => panic!("Could not update metadata in string! Error: {:?}", e),
                         },
-                        _ => println!("{}", ed.format(minimal).unwrap()),
+                        _ => println!("{}", ed.format_with(&options).unwrap()),
                     }
                 }
                 _ => panic!("Metadata not recognised or given!"),
@@ -415,8 +744,27 @@ fn query(matches: &ArgMatches) -> () {
         Some("report") => {
             info!("Printing data for parsing/reading.");
             match matches.value_of("format") {
-                Some("json") => println!("{}", ed.format_json().unwrap()),
-                Some("human") => print!("{}", ed.format_readable().unwrap()),
+                Some("json") => {
+                    println!("{}", ed.format_json().unwrap());
+                    if let Some(c) = &curve {
+                        println!("{}", c.format_json().unwrap());
+                    }
+                }
+                Some("human") => {
+                    print!("{}", ed.format_readable().unwrap());
+                    // Tags that disagree with the one used as the actual tempo
+                    if let Some(tm) = &track_metadata {
+                        for conflict in tm.tempo_conflicts() {
+                            println!(
+                                "Conflicting tag: {}, Tempo: {}",
+                                conflict.source, conflict.bpm
+                            );
+                        }
+                    }
+                    if let Some(c) = &curve {
+                        print!("{}", c.format_readable());
+                    }
+                }
                 _ => panic!("Format data not recognised or given!"),
             }
         }
@@ -426,6 +774,25 @@ fn query(matches: &ArgMatches) -> () {
     };
 }
 
+// Check that the estimators given on the command line are all registered (or
+// the ensemble), before reading any tracks, exiting with a usage error if not.
+fn check_estimators(matches: &ArgMatches) {
+    let estimators = matches.value_of("estimators").unwrap_or("all");
+    if estimators == "all" {
+        return;
+    }
+    let registry = registry::registry();
+    for name in estimators.split(',').map(|name| name.trim()) {
+        if name != AlgorithmE::Ensemble.print() && registry.lookup(name).is_none() {
+            invalid_value(&format!(
+                "'{}' is not a registered estimator for '--estimators' [registered: {}, ensemble]",
+                name,
+                registry.names().join(", ")
+            ));
+        }
+    }
+}
+
 #[flame]
 fn main() {
     env_logger::init();
@@ -438,10 +805,34 @@ fn main() {
 
     info!("Application started");
 
+    // Register any estimators defined in the configuration file. The default
+    // configuration file need not exist (or be valid), but one given explicitly
+    // must be.
+    let estimator_config = Path::new(matches.value_of("estimator_config").unwrap());
+    let explicit = matches.occurrences_of("estimator_config") > 0;
+    if explicit || estimator_config.exists() {
+        match command::register_config(estimator_config) {
+            Ok(names) => info!("Registered configured estimators: {:?}", names),
+            Err(e) if explicit => panic!(
+                "Could not use estimator configuration from {:?}, {}!",
+                estimator_config, e
+            ),
+            Err(e) => error!(
+                "Could not use estimator configuration from {:?}, {}, continuing without it",
+                estimator_config, e
+            ),
+        }
+    }
+
     match subcommands {
         ("init", Some(sub)) => init(sub),
         ("dump", Some(sub)) => dump(sub),
-        ("query", Some(sub)) => query(sub),
+        ("beats", Some(sub)) => beats(sub),
+        ("properties", Some(sub)) => properties(sub),
+        ("query", Some(sub)) => {
+            check_estimators(sub);
+            query(sub)
+        }
         _ => {
             appm.print_help().unwrap();
             println!();
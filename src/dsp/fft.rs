@@ -0,0 +1,84 @@
+use std::f64::consts::PI;
+
+// An in-place, iterative, radix-2 fast fourier transform. The real and
+// imaginary parts must have the same length, which must be a power of two.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    // reorder the input by bit-reversed index
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    // then perform successively larger butterflies
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f64;
+        let (wr, wi) = (angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let (mut cr, mut ci) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let (a, b) = (start + k, start + k + len / 2);
+                let br = re[b] * cr - im[b] * ci;
+                let bi = re[b] * ci + im[b] * cr;
+                re[b] = re[a] - br;
+                im[b] = im[a] - bi;
+                re[a] += br;
+                im[a] += bi;
+                let next = cr * wr - ci * wi;
+                ci = cr * wi + ci * wr;
+                cr = next;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// The magnitude spectrum (bins 0..=n/2) of a real valued frame, whose length must be a power of two.
+pub fn magnitudes(frame: &[f64]) -> Vec<f64> {
+    let mut re = frame.to_vec();
+    let mut im = vec![0.0; frame.len()];
+    fft(&mut re, &mut im);
+    re.iter()
+        .zip(im.iter())
+        .take(frame.len() / 2 + 1)
+        .map(|(r, i)| (r * r + i * i).sqrt())
+        .collect()
+}
+
+// A hann window of a given length.
+pub fn hann(length: usize) -> Vec<f64> {
+    (0..length)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / length as f64).cos())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sinusoid() {
+        let frame: Vec<f64> = (0..64)
+            .map(|i| (2.0 * PI * 4.0 * i as f64 / 64.0).sin())
+            .collect();
+        let spectrum = magnitudes(&frame);
+        assert_eq!(spectrum.len(), 33);
+        assert!((spectrum[4] - 32.0).abs() < 1e-9);
+        assert!(spectrum
+            .iter()
+            .enumerate()
+            .all(|(bin, m)| bin == 4 || *m < 1e-9));
+    }
+}
@@ -0,0 +1,44 @@
+/*
+    Signal processing primitives, used by the in-process (pure rust)
+    estimators and analysers.
+*/
+pub mod fft;
+pub mod onset;
+
+// The (biased) autocorrelation of a signal, for lags 0..max_lag (inclusive)
+pub fn autocorrelation(signal: &[f64], max_lag: usize) -> Vec<f64> {
+    let n = signal.len();
+    (0..=max_lag)
+        .map(|lag| {
+            if lag >= n {
+                return 0.0;
+            }
+            signal[..n - lag]
+                .iter()
+                .zip(&signal[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / n as f64
+        })
+        .collect()
+}
+
+// Find the local maxima of a signal within [from, to), returning their
+// (fractional, using parabolic interpolation) positions and values.
+pub fn peaks(signal: &[f64], from: usize, to: usize) -> Vec<(f64, f64)> {
+    let from = from.max(1);
+    let to = to.min(signal.len().saturating_sub(1));
+    (from..to)
+        .filter(|&i| signal[i] > signal[i - 1] && signal[i] >= signal[i + 1])
+        .map(|i| {
+            let (a, b, c) = (signal[i - 1], signal[i], signal[i + 1]);
+            let denominator = a - 2.0 * b + c;
+            let offset = if denominator != 0.0 {
+                0.5 * (a - c) / denominator
+            } else {
+                0.0
+            };
+            (i as f64 + offset, b - 0.25 * (a - c) * offset)
+        })
+        .collect()
+}
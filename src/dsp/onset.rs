@@ -0,0 +1,134 @@
+/*
+    Onset detection functions, i.e. signals that measure how much "new"
+    energy is present at each point in an audio stream.
+*/
+use dsp::fft::*;
+
+#[derive(Debug, Clone)]
+pub struct OnsetEnvelope {
+    pub values: Vec<f64>, // the onset strength of each frame
+    pub frame_rate: f64,  // the number of frames per second
+}
+
+impl OnsetEnvelope {
+    // The size (in samples) of the frames we take spectra of
+    pub const FRAME_SIZE: usize = 2048;
+    // The distance (in samples) between frames, 10ms at 44.1KHz
+    pub const HOP_SIZE: usize = 441;
+    // The amount of log compression applied to the spectra
+    const COMPRESSION: f64 = 100.0;
+    // The length (in seconds) of the window used to remove the local mean
+    const MEAN_WINDOW: f64 = 0.5;
+    // The kernel used to smooth the envelope
+    const SMOOTHING: [f64; 5] = [0.1, 0.2, 0.4, 0.2, 0.1];
+
+    // Compute a spectral flux onset envelope from a (mono) stream of samples
+    #[flame("OnsetEnvelope")]
+    pub fn spectral_flux(samples: &[f32], sample_rate: f64) -> OnsetEnvelope {
+        let window = hann(Self::FRAME_SIZE);
+        let frame_rate = sample_rate / Self::HOP_SIZE as f64;
+
+        let mut flux: Vec<f64> = vec![];
+        let mut previous: Option<Vec<f64>> = None;
+        let mut start = 0;
+        while start + Self::FRAME_SIZE <= samples.len() {
+            let frame: Vec<f64> = samples[start..start + Self::FRAME_SIZE]
+                .iter()
+                .zip(window.iter())
+                .map(|(s, w)| *s as f64 * w)
+                .collect();
+            let spectrum: Vec<f64> = magnitudes(&frame)
+                .iter()
+                .map(|m| (1.0 + Self::COMPRESSION * m).ln())
+                .collect();
+            // the flux is the total (positive) increase in energy across all bins
+            flux.push(match &previous {
+                Some(p) => spectrum
+                    .iter()
+                    .zip(p.iter())
+                    .map(|(s, p)| (s - p).max(0.0))
+                    .sum(),
+                None => 0.0,
+            });
+            previous = Some(spectrum);
+            start += Self::HOP_SIZE;
+        }
+
+        OnsetEnvelope {
+            values: Self::normalise(&flux, (Self::MEAN_WINDOW * frame_rate) as usize),
+            frame_rate,
+        }
+    }
+
+    // Subtract the local mean, half-wave rectify, smooth, and scale to unit variance
+    fn normalise(flux: &[f64], window: usize) -> Vec<f64> {
+        let half = window / 2;
+        let rectified: Vec<f64> = (0..flux.len())
+            .map(|i| {
+                let from = i.saturating_sub(half);
+                let to = (i + half + 1).min(flux.len());
+                let mean = flux[from..to].iter().sum::<f64>() / (to - from) as f64;
+                (flux[i] - mean).max(0.0)
+            })
+            .collect();
+        // Smoothing spreads each onset over a few frames, so that periodicities
+        // that aren't a whole number of frames still correlate well.
+        let rectified: Vec<f64> = (0..rectified.len())
+            .map(|i| {
+                Self::SMOOTHING
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(k, w)| {
+                        (i + k)
+                            .checked_sub(Self::SMOOTHING.len() / 2)
+                            .and_then(|j| rectified.get(j))
+                            .map(|v| v * w)
+                    })
+                    .sum()
+            })
+            .collect();
+        let n = rectified.len().max(1) as f64;
+        let mean = rectified.iter().sum::<f64>() / n;
+        let deviation = (rectified.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+        if deviation > 0.0 {
+            rectified.iter().map(|v| v / deviation).collect()
+        } else {
+            rectified
+        }
+    }
+
+    // The time (in seconds) of a (fractional) frame
+    pub fn time(&self, frame: f64) -> f64 {
+        (frame * Self::HOP_SIZE as f64 + Self::FRAME_SIZE as f64 / 2.0)
+            / (self.frame_rate * Self::HOP_SIZE as f64)
+    }
+
+    // The (fractional) frame at a time (in seconds)
+    pub fn frame(&self, time: f64) -> f64 {
+        (time * self.frame_rate * Self::HOP_SIZE as f64 - Self::FRAME_SIZE as f64 / 2.0)
+            / Self::HOP_SIZE as f64
+    }
+
+    // The lag (in frames) of a beat period at a given tempo
+    pub fn lag(&self, bpm: f64) -> f64 {
+        60.0 * self.frame_rate / bpm
+    }
+
+    // The tempo of a beat period with a given lag (in frames)
+    pub fn bpm(&self, lag: f64) -> f64 {
+        60.0 * self.frame_rate / lag
+    }
+
+    // The shortest and longest lags (in frames) of beat periods between two
+    // tempos, limited to the length of the envelope, or None if the tempos
+    // aren't a range that we can search (i.e. 0 < min_bpm < max_bpm)
+    pub fn lags(&self, min_bpm: f64, max_bpm: f64) -> Option<(usize, usize)> {
+        if !(min_bpm > 0.0 && min_bpm < max_bpm && max_bpm.is_finite()) {
+            return None;
+        }
+        let longest = self.values.len().max(1);
+        let min_lag = (self.lag(max_bpm).floor() as usize).max(1).min(longest);
+        let max_lag = (self.lag(min_bpm).ceil() as usize).min(longest);
+        Some((min_lag, max_lag.max(min_lag)))
+    }
+}
@@ -0,0 +1,162 @@
+/*
+    A resonating comb filter bank tempo estimator, following the tempo
+    estimation stage of:
+
+        Böck, Krebs and Widmer, "Accurate Tempo Estimation based on Recurrent
+        Neural Networks and Resonating Comb Filters", ISMIR 2015
+
+    Rather than the activation function of a neural network, we use the
+    spectral flux onset envelope as our activation function. The envelope is
+    fed through a bank of comb filters (one for each beat period that we are
+    interested in), and at each frame the filter that resonates the most
+    votes for its tempo. The tempo is chosen from the peaks of the resulting
+    histogram.
+*/
+use dsp::onset::OnsetEnvelope;
+use dsp::*;
+use estimators::context::AnalysisContext;
+use estimators::*;
+use std::path::PathBuf;
+use types::*;
+
+pub struct CombFilterTempoEstimator {
+    pub params: EstimatorParams,
+}
+
+impl CombFilterTempoEstimator {
+    // The gain of the feedback loop of each comb filter, as used in the paper
+    const ALPHA: f64 = 0.79;
+
+    pub fn new(params: &EstimatorParams) -> CombFilterTempoEstimator {
+        CombFilterTempoEstimator {
+            params: params.clone(),
+        }
+    }
+
+    // Build a histogram of the dominant beat period (in frames) of each frame of an envelope
+    pub fn histogram(&self, envelope: &OnsetEnvelope) -> Vec<f64> {
+        let (min_lag, max_lag) = match envelope.lags(self.params.min_bpm, self.params.max_bpm) {
+            Some(lags) => lags,
+            None => {
+                error!(
+                    "Cannot search for tempos between {} and {} bpm",
+                    self.params.min_bpm, self.params.max_bpm
+                );
+                return vec![];
+            }
+        };
+        let x = &envelope.values;
+
+        // run each comb filter of the bank over the envelope in turn, keeping the
+        // filter that resonates the most at each frame, where
+        //   y[frame] = x[frame] + alpha * y[frame - lag]
+        let mut best: Vec<(usize, f64)> = vec![(0, 0.0); x.len()];
+        let mut y = vec![0.0; x.len()];
+        for lag in min_lag..=max_lag {
+            y.copy_from_slice(x);
+            for frame in lag..y.len() {
+                y[frame] += Self::ALPHA * y[frame - lag];
+            }
+            for (b, value) in best.iter_mut().zip(y.iter()) {
+                if *value > b.1 {
+                    *b = (lag, *value);
+                }
+            }
+        }
+
+        let mut histogram = vec![0.0; max_lag + 2];
+        for (lag, value) in best {
+            if lag > 0 {
+                histogram[lag] += value;
+            }
+        }
+        histogram
+    }
+
+    // Estimate a tempo from the peaks of the comb filter histogram of an onset envelope
+    pub fn estimate(&self, envelope: &OnsetEnvelope) -> Option<TempoEstimate> {
+        let histogram = self.histogram(envelope);
+        let candidates: Vec<TempoCandidate> = peaks(&histogram, 1, histogram.len())
+            .into_iter()
+            .filter(|(_, strength)| *strength > 0.0)
+            .map(|(lag, strength)| TempoCandidate {
+                bpm: envelope.bpm(lag),
+                strength,
+            })
+            .filter(|c| c.bpm >= self.params.min_bpm && c.bpm <= self.params.max_bpm)
+            .collect();
+        debug!("Comb filter candidates: {:?}", candidates);
+
+        TempoEstimate::from_candidates(candidates)
+    }
+
+    // Estimate the tempo of a (mono) stream of samples
+    pub fn analyse(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        self.estimate(&OnsetEnvelope::spectral_flux(samples, SAMPLE_RATE))
+    }
+}
+
+impl TempoEstimator for CombFilterTempoEstimator {
+    #[flame("CombFilterTempoEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        self.run_context(&AnalysisContext::new(audio_file, &self.params))
+    }
+
+    fn run_samples(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        self.analyse(samples)
+    }
+
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        self.estimate(&*context.envelope()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::*;
+
+    #[test]
+    fn histogram() {
+        // an impulse every 40 frames resonates with the filter of that lag
+        let envelope = OnsetEnvelope {
+            values: (0..2000)
+                .map(|i| if i % 40 == 0 { 1.0 } else { 0.0 })
+                .collect(),
+            frame_rate: 100.0,
+        };
+        let estimator = CombFilterTempoEstimator::new(&EstimatorParams::default());
+        let histogram = estimator.histogram(&envelope);
+        let peak = (0..histogram.len())
+            .max_by(|a, b| histogram[*a].partial_cmp(&histogram[*b]).unwrap())
+            .unwrap();
+        assert_eq!(peak, 40);
+
+        // a range of tempos that we can't search gives an empty histogram, and
+        // very slow tempos are limited to the length of the envelope
+        let params = |min_bpm| EstimatorParams {
+            min_bpm,
+            ..EstimatorParams::default()
+        };
+        let histogram =
+            |min_bpm| CombFilterTempoEstimator::new(&params(min_bpm)).histogram(&envelope);
+        assert!(histogram(0.0).is_empty());
+        assert!(histogram(-1.0).is_empty());
+        assert_eq!(histogram(1e-9).len(), envelope.values.len() + 2);
+    }
+
+    #[test]
+    fn click_track() {
+        let estimator = CombFilterTempoEstimator::new(&EstimatorParams::default());
+        for bpm in [100.0, 140.0, 180.0].iter() {
+            let samples = render(&clicks(*bpm, 20.0), 20.0);
+            let estimate = estimator.analyse(&samples).unwrap();
+            assert!(
+                (estimate.bpm - bpm).abs() < 2.0,
+                "Estimated {} for a click track at {}",
+                estimate.bpm,
+                bpm
+            );
+        }
+    }
+}
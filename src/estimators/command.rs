@@ -0,0 +1,421 @@
+/*
+    Estimators defined by configuration, rather than compiled into ellington.
+    Each runs an external command over the audio file (e.g. aubio, essentia,
+    sonic-annotator, or a script), and extracts the tempo from its output,
+    either with a regular expression, or a path into JSON output.
+
+    The configuration is a JSON list of estimators, e.g.:
+
+    [
+        {
+            "name": "aubio",
+            "command": ["aubio", "tempo", "{file}"],
+            "regex": "(\\d+(?:\\.\\d+)?) bpm"
+        },
+        {
+            "name": "essentia",
+            "command": ["essentia_streaming_extractor_music", "{file}", "-"],
+            "json_path": "rhythm.bpm"
+        },
+        {
+            "name": "bellson",
+            "algorithm": "bellson",
+            "command": ["bellson-worker"],
+            "worker": true
+        }
+    ]
+
+    Commands are killed if they run for longer than "timeout" seconds, and
+    are re-run up to "retries" times if they fail, or time out. Setting a
+    "version" (e.g. when a model is retrained) marks earlier results as stale.
+
+    Estimators with "worker" set are started once, and then kept running and
+    sent each track in turn, using the protocol in shelltools::worker.
+*/
+use estimators::registry::*;
+use estimators::*;
+use regex::Regex;
+use serde_json;
+use serde_json::Value;
+use shelltools::generic::ShellProgram;
+use shelltools::template::TemplateCommand;
+use shelltools::worker::*;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use types::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandEstimatorConfig {
+    pub name: String, // the name of the estimator, as given on the command line
+    pub short_code: Option<String>, // a short name for the estimator, defaults to the name
+    // the key under which results are stored (e.g. "bellson", to replace bellson with
+    // a script), defaults to the name
+    pub algorithm: Option<String>,
+    pub command: Vec<String>, // the command to run, with "{file}" in place of the audio file
+    #[serde(default)]
+    pub regex: Option<String>, // a regex whose first capture group is the tempo
+    #[serde(default)]
+    pub json_path: Option<String>, // or a (dot separated) path to the tempo in json output
+    #[serde(default = "CommandEstimatorConfig::correctable_default")]
+    pub correctable: bool,
+    #[serde(default)]
+    pub worker: bool, // whether the command starts a persistent worker
+    #[serde(default)]
+    pub timeout: Option<f64>, // how long (in seconds) to wait for the tempo of a track
+    #[serde(default)]
+    pub retries: u32, // how many times to re-run a (one-shot) command that fails
+    // the version of the estimator, which should be changed whenever its results might
+    // (e.g. when a model is retrained). Defaults to a fingerprint of the command.
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+impl CommandEstimatorConfig {
+    fn correctable_default() -> bool {
+        true
+    }
+}
+
+// How to extract a tempo from the output of a program.
+#[derive(Debug, Clone)]
+pub enum TempoExtractor {
+    Regex(Regex),
+    JsonPath(Vec<String>),
+}
+
+impl TempoExtractor {
+    pub fn extract(&self, output: &str) -> Option<f64> {
+        match self {
+            TempoExtractor::Regex(re) => {
+                let captures = re.captures(output)?;
+                debug!("Captures: {:?}", captures);
+                captures.get(1)?.as_str().parse::<f64>().ok()
+            }
+            TempoExtractor::JsonPath(path) => {
+                let json: Value = serde_json::from_str(output).ok()?;
+                let value = path.iter().try_fold(&json, |value, key| match value {
+                    Value::Array(values) => values.get(key.parse::<usize>().ok()?),
+                    object => object.get(key),
+                })?;
+                debug!("Json value: {:?}", value);
+                match value {
+                    Value::Number(n) => n.as_f64(),
+                    Value::String(s) => s.trim().parse::<f64>().ok(),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+pub struct CommandTempoEstimator {
+    pub command: Vec<String>,
+    pub extractor: TempoExtractor,
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+impl TempoEstimator for CommandTempoEstimator {
+    #[flame("CommandTempoEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        let mut call = TemplateCommand::new(&self.command, audio_file)?;
+        call.timeout = self.timeout;
+        call.retries = self.retries;
+        match call.run() {
+            Some((stdout, _stderr)) => {
+                let bpm = self.extractor.extract(stdout.as_str()).or_else(|| {
+                    error!(
+                        "Could not find a tempo in the output of {:?}",
+                        call.as_shell_args()
+                    );
+                    None
+                })?;
+                Some(TempoEstimate::from_bpm(bpm, None))
+            }
+            _ => {
+                error!("Got error while running {:?}!", call.as_shell_args());
+                None
+            }
+        }
+    }
+}
+
+// Asks a (shared) persistent worker for the tempo of each track.
+pub struct WorkerTempoEstimator {
+    pub worker: Arc<Mutex<Worker>>,
+}
+
+impl TempoEstimator for WorkerTempoEstimator {
+    #[flame("WorkerTempoEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        let mut worker = match self.worker.lock() {
+            Ok(worker) => worker,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match worker.request(audio_file)? {
+            WorkerResponse {
+                bpm: Some(bpm),
+                confidence,
+                ..
+            } => Some(TempoEstimate::from_bpm(bpm, confidence)),
+            response => {
+                error!(
+                    "Worker could not estimate the tempo of {:?}: {:?}",
+                    audio_file, response.error
+                );
+                None
+            }
+        }
+    }
+}
+
+pub enum CommandKind {
+    OneShot(TempoExtractor),    // the command is run once per track
+    Worker(Arc<Mutex<Worker>>), // the command is started once, and shared
+}
+
+pub struct CommandEstimatorFactory {
+    pub config: CommandEstimatorConfig,
+    pub kind: CommandKind,
+}
+
+impl CommandEstimatorFactory {
+    // Check a configuration, and create a factory from it.
+    pub fn from_config(mut config: CommandEstimatorConfig) -> Option<CommandEstimatorFactory> {
+        if config.command.is_empty() {
+            error!("No command given for estimator {:?}", config.name);
+            return None;
+        }
+        if config.version.is_none() {
+            config.version = Some(fingerprint(&config.command.join(" ")));
+        }
+        if config.worker {
+            if config.regex.is_some() || config.json_path.is_some() {
+                error!(
+                    "Worker estimator {:?} reports tempos itself, so takes no regex or json path",
+                    config.name
+                );
+                return None;
+            }
+            let mut worker_config = WorkerConfig::new(config.command.clone());
+            if let Some(timeout) = config.timeout {
+                worker_config.request_timeout = timeout;
+            }
+            let worker = Arc::new(Mutex::new(Worker::new(worker_config)));
+            return Some(CommandEstimatorFactory {
+                config,
+                kind: CommandKind::Worker(worker),
+            });
+        }
+        if !config
+            .command
+            .iter()
+            .any(|part| part.contains(TemplateCommand::FILE_PLACEHOLDER))
+        {
+            warn!(
+                "The command for estimator {:?} never mentions {}",
+                config.name,
+                TemplateCommand::FILE_PLACEHOLDER
+            );
+        }
+        let extractor = match (&config.regex, &config.json_path) {
+            (Some(re), None) => match Regex::new(re) {
+                Ok(re) => TempoExtractor::Regex(re),
+                Err(e) => {
+                    error!("Invalid regex for estimator {:?}: {:?}", config.name, e);
+                    return None;
+                }
+            },
+            (None, Some(path)) => {
+                TempoExtractor::JsonPath(path.split('.').map(|k| k.to_string()).collect())
+            }
+            _ => {
+                error!(
+                    "Estimator {:?} needs exactly one of a regex, or a json path",
+                    config.name
+                );
+                return None;
+            }
+        };
+        Some(CommandEstimatorFactory {
+            config,
+            kind: CommandKind::OneShot(extractor),
+        })
+    }
+}
+
+impl EstimatorFactory for CommandEstimatorFactory {
+    fn name(&self) -> &str {
+        self.config.name.as_str()
+    }
+
+    fn short_code(&self) -> &str {
+        self.config
+            .short_code
+            .as_ref()
+            .unwrap_or(&self.config.name)
+            .as_str()
+    }
+
+    fn algorithm(&self) -> AlgorithmE {
+        AlgorithmE::parse(self.config.algorithm.as_ref().unwrap_or(&self.config.name))
+    }
+
+    fn version(&self) -> &str {
+        self.config.version.as_ref().map_or("", |v| v.as_str())
+    }
+
+    fn construct(&self, _params: &EstimatorParams) -> Box<dyn TempoEstimator> {
+        match &self.kind {
+            CommandKind::OneShot(extractor) => Box::new(CommandTempoEstimator {
+                command: self.config.command.clone(),
+                extractor: extractor.clone(),
+                timeout: self
+                    .config
+                    .timeout
+                    .map(|t| Duration::from_millis((t * 1000.0) as u64)),
+                retries: self.config.retries,
+            }),
+            CommandKind::Worker(worker) => Box::new(WorkerTempoEstimator {
+                worker: worker.clone(),
+            }),
+        }
+    }
+
+    fn correctable(&self) -> bool {
+        self.config.correctable
+    }
+}
+
+// Read the estimators defined in a configuration file
+pub fn read_config(path: &Path) -> Option<Vec<CommandEstimatorConfig>> {
+    info!("Reading estimator configuration from {:?}", path);
+    let json = match fs::read_to_string(path) {
+        Ok(j) => Some(j),
+        Err(e) => {
+            error!(
+                "Error reading estimator configuration {:?}, got io error {:?}",
+                path, e
+            );
+            None
+        }
+    }?;
+    match serde_json::from_str::<Vec<CommandEstimatorConfig>>(&json) {
+        Ok(configs) => Some(configs),
+        Err(e) => {
+            error!(
+                "Failed to parse estimator configuration {:?}! Serde error {:?}",
+                path, e
+            );
+            None
+        }
+    }
+}
+
+// Why the estimators in a configuration file could not be registered
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Unreadable,           // the file couldn't be read, or isn't a list of estimators
+    Invalid(Vec<String>), // the names of the estimators that are configured wrongly
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Unreadable => write!(f, "could not read the configuration"),
+            ConfigError::Invalid(names) => {
+                write!(f, "invalid configuration for estimators {:?}", names)
+            }
+        }
+    }
+}
+
+// Register the estimators defined in a configuration file with the global
+// registry, returning the names of those that were registered. If any of them
+// are configured wrongly, none of them are registered.
+pub fn register_config(path: &Path) -> Result<Vec<String>, ConfigError> {
+    let mut factories = vec![];
+    let mut invalid = vec![];
+    for config in read_config(path).ok_or(ConfigError::Unreadable)? {
+        let name = config.name.clone();
+        match CommandEstimatorFactory::from_config(config) {
+            Some(factory) => factories.push(factory),
+            None => invalid.push(name),
+        }
+    }
+    if !invalid.is_empty() {
+        return Err(ConfigError::Invalid(invalid));
+    }
+    let names = factories.iter().map(|f| f.name().to_string()).collect();
+    for factory in factories {
+        register(factory);
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> Option<CommandEstimatorFactory> {
+        CommandEstimatorFactory::from_config(serde_json::from_str(json).unwrap())
+    }
+
+    fn extract(factory: &CommandEstimatorFactory, output: &str) -> Option<f64> {
+        match &factory.kind {
+            CommandKind::OneShot(extractor) => extractor.extract(output),
+            CommandKind::Worker(_) => panic!("Not a one-shot command"),
+        }
+    }
+
+    #[test]
+    fn regex() {
+        let factory = config(
+            r#"{"name": "aubio", "command": ["aubio", "tempo", "{file}"], "regex": "(\\d+(?:\\.\\d+)?) bpm"}"#,
+        )
+        .unwrap();
+        assert_eq!(factory.algorithm(), AlgorithmE::Custom("aubio".to_string()));
+        assert_eq!(extract(&factory, "121.4 bpm\n"), Some(121.4));
+        assert_eq!(extract(&factory, "no tempo"), None);
+    }
+
+    #[test]
+    fn json_path() {
+        let factory = config(
+            r#"{"name": "script", "algorithm": "bellson", "command": ["./tempo.py", "{file}"], "json_path": "tempos.1.bpm"}"#,
+        )
+        .unwrap();
+        assert_eq!(factory.algorithm(), AlgorithmE::Bellson);
+        assert_eq!(
+            extract(&factory, r#"{"tempos": [{"bpm": 90}, {"bpm": "180.5"}]}"#),
+            Some(180.5)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(config(r#"{"name": "none", "command": ["true"]}"#).is_none());
+        assert!(config(r#"{"name": "empty", "command": [], "regex": "(\\d+)"}"#).is_none());
+
+        // a configuration file with any invalid estimators registers none of them
+        let path =
+            std::env::temp_dir().join(format!("ellington-config-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[{"name": "config-good", "command": ["aubio", "{file}"], "regex": "(\\d+)"},
+                {"name": "config-bad", "command": ["aubio", "{file}"], "regex": "(\\d+"}]"#,
+        )
+        .unwrap();
+        let registered = register_config(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            registered,
+            Err(ConfigError::Invalid(vec!["config-bad".to_string()]))
+        );
+        assert!(lookup("config-good").is_none());
+        assert_eq!(register_config(&path), Err(ConfigError::Unreadable));
+    }
+}
@@ -0,0 +1,88 @@
+/*
+    The shared state of analysing a single track. Audio is decoded (at most)
+    once, and features derived from it (e.g. the onset envelope) are computed
+    once, no matter how many estimators or analysers look at the track.
+*/
+use dsp::onset::OnsetEnvelope;
+use estimators::*;
+use library::trackmetadata::TrackMetadata;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Compute a value the first time that it's asked for, and remember it
+// (including failures, so that we don't retry e.g. undecodable files).
+fn cached<T: Clone, F: FnOnce() -> T>(cell: &RefCell<Option<T>>, compute: F) -> T {
+    if let Some(value) = cell.borrow().as_ref() {
+        return value.clone();
+    }
+    let value = compute();
+    *cell.borrow_mut() = Some(value.clone());
+    value
+}
+
+pub struct AnalysisContext {
+    pub audio_file: PathBuf,
+    pub params: EstimatorParams, // decides how much of the track is decoded
+    samples: RefCell<Option<Option<Rc<Vec<f32>>>>>,
+    envelope: RefCell<Option<Option<Rc<OnsetEnvelope>>>>,
+    metadata: RefCell<Option<Option<TrackMetadata>>>,
+}
+
+impl AnalysisContext {
+    pub fn new(audio_file: &Path, params: &EstimatorParams) -> AnalysisContext {
+        AnalysisContext {
+            audio_file: audio_file.to_path_buf(),
+            params: params.clone(),
+            samples: RefCell::new(None),
+            envelope: RefCell::new(None),
+            metadata: RefCell::new(None),
+        }
+    }
+
+    // A context over samples that have already been decoded.
+    pub fn from_samples(samples: Vec<f32>, params: &EstimatorParams) -> AnalysisContext {
+        let context = AnalysisContext::new(Path::new(""), params);
+        *context.samples.borrow_mut() = Some(Some(Rc::new(samples)));
+        *context.metadata.borrow_mut() = Some(None);
+        context
+    }
+
+    // The (mono) samples of the track, decoded on first use
+    pub fn samples(&self) -> Option<Rc<Vec<f32>>> {
+        cached(&self.samples, || {
+            info!("Decoding {:?}", self.audio_file);
+            decode(&self.audio_file, &self.params).map(Rc::new)
+        })
+    }
+
+    // The (spectral flux) onset envelope of the track
+    pub fn envelope(&self) -> Option<Rc<OnsetEnvelope>> {
+        cached(&self.envelope, || {
+            let samples = self.samples()?;
+            Some(Rc::new(OnsetEnvelope::spectral_flux(&samples, SAMPLE_RATE)))
+        })
+    }
+
+    // The metadata (tags) of the track
+    pub fn metadata(&self) -> Option<TrackMetadata> {
+        cached(&self.metadata, || {
+            TrackMetadata::from_file(self.audio_file.as_path())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_are_shared() {
+        let context = AnalysisContext::from_samples(vec![0.0; 44100], &EstimatorParams::default());
+        let samples = context.samples().unwrap();
+        assert!(Rc::ptr_eq(&samples, &context.samples().unwrap()));
+        let envelope = context.envelope().unwrap();
+        assert!(Rc::ptr_eq(&envelope, &context.envelope().unwrap()));
+        assert!(context.metadata().is_none());
+    }
+}
@@ -0,0 +1,224 @@
+/*
+    Decoders turn audio files into (mono) streams of samples, at SAMPLE_RATE.
+    We decode in-process with hodges where we can, and fall back to streaming
+    the output of ffmpeg, and then sox, for files that hodges can't open
+    (e.g. some M4P or ALAC files).
+*/
+use byteorder::{ByteOrder, LittleEndian};
+use estimators::SAMPLE_RATE;
+use hodges::*;
+use shelltools::ffmpeg::FfmpegCommand;
+use shelltools::sox::SoxCommand;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Child;
+
+pub trait Decoder {
+    fn name(&self) -> &str;
+
+    // Decode (at most max_samples of) an audio file to a mono stream of samples
+    fn decode(&self, audio_file: &Path, max_samples: Option<usize>) -> Option<Vec<f32>>;
+}
+
+pub struct HodgesDecoder {}
+
+impl Decoder for HodgesDecoder {
+    fn name(&self) -> &str {
+        "hodges"
+    }
+
+    fn decode(&self, audio_file: &Path, max_samples: Option<usize>) -> Option<Vec<f32>> {
+        let state: State<&[f32]> = State::from_file(audio_file.to_path_buf())?;
+        let samples = state.flatten().cloned();
+        Some(match max_samples {
+            Some(n) => samples.take(n).collect(),
+            None => samples.collect(),
+        })
+    }
+}
+
+// Read (at most max_samples) raw 32 bit little endian float samples from a stream
+pub fn read_samples<R: Read>(stream: &mut R, max_samples: Option<usize>) -> io::Result<Vec<f32>> {
+    let mut bytes = vec![];
+    match max_samples {
+        Some(n) => stream.take(n as u64 * 4).read_to_end(&mut bytes)?,
+        None => stream.read_to_end(&mut bytes)?,
+    };
+    let mut samples = vec![0.0; bytes.len() / 4];
+    LittleEndian::read_f32_into(&bytes[..samples.len() * 4], &mut samples);
+    Ok(samples)
+}
+
+// Read the samples that a (decoding) program writes to stdout, and stop it
+// once we have as many as we want.
+fn read_child(name: &str, mut child: Child, max_samples: Option<usize>) -> Option<Vec<f32>> {
+    let result = match child.stdout.as_mut() {
+        Some(stdout) => read_samples(stdout, max_samples),
+        None => Ok(vec![]),
+    };
+    // we may have stopped reading early, so the program may still be running
+    let _ = child.kill();
+    let _ = child.wait();
+    match result {
+        Ok(ref samples) if samples.is_empty() => {
+            error!("{} decoded no samples", name);
+            None
+        }
+        Ok(samples) => Some(samples),
+        Err(e) => {
+            error!("Got error {:?} while reading samples from {}", e, name);
+            None
+        }
+    }
+}
+
+// Programs are given their arguments as strings, so can't be given a path
+// that isn't valid unicode.
+fn printable(name: &str, audio_file: &Path) -> bool {
+    if audio_file.to_str().is_none() {
+        error!(
+            "Cannot pass the path {:?} to {}, as it is not valid unicode",
+            audio_file, name
+        );
+    }
+    audio_file.to_str().is_some()
+}
+
+// Streams the output of ffmpeg, as f32le mono at 44.1KHz
+pub struct FfmpegDecoder {}
+
+impl Decoder for FfmpegDecoder {
+    fn name(&self) -> &str {
+        "ffmpeg"
+    }
+
+    fn decode(&self, audio_file: &Path, max_samples: Option<usize>) -> Option<Vec<f32>> {
+        if !printable(self.name(), audio_file) {
+            return None;
+        }
+        match FfmpegCommand::default(&audio_file.to_path_buf()).spawn() {
+            Ok(child) => read_child(self.name(), child, max_samples),
+            Err(e) => {
+                error!("Could not start ffmpeg: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+// Streams the output of sox, as raw 32 bit float mono at 44.1KHz
+pub struct SoxDecoder {}
+
+impl Decoder for SoxDecoder {
+    fn name(&self) -> &str {
+        "sox"
+    }
+
+    fn decode(&self, audio_file: &Path, max_samples: Option<usize>) -> Option<Vec<f32>> {
+        if !printable(self.name(), audio_file) {
+            return None;
+        }
+        match SoxCommand::default(&audio_file.to_path_buf()).spawn() {
+            Ok(child) => read_child(self.name(), child, max_samples),
+            Err(e) => {
+                error!("Could not start sox: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+// The decoders that we try, in order
+pub fn decoders() -> Vec<Box<dyn Decoder>> {
+    vec![
+        Box::new(HodgesDecoder {}),
+        Box::new(FfmpegDecoder {}),
+        Box::new(SoxDecoder {}),
+    ]
+}
+
+// Decode an audio file with the first of the decoders that can
+pub fn decode_with(
+    decoders: &[Box<dyn Decoder>],
+    audio_file: &Path,
+    max_samples: Option<usize>,
+) -> Option<Vec<f32>> {
+    for decoder in decoders {
+        match decoder.decode(audio_file, max_samples) {
+            Some(samples) => {
+                info!(
+                    "Decoded {} samples ({:.1}s) from {:?} with {}",
+                    samples.len(),
+                    samples.len() as f64 / SAMPLE_RATE,
+                    audio_file,
+                    decoder.name()
+                );
+                return Some(samples);
+            }
+            None => info!("Could not decode {:?} with {}", audio_file, decoder.name()),
+        }
+    }
+    error!("Could not decode {:?} with any decoder", audio_file);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    struct FixedDecoder {
+        samples: Option<Vec<f32>>,
+    }
+
+    impl Decoder for FixedDecoder {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        fn decode(&self, _audio_file: &Path, _max_samples: Option<usize>) -> Option<Vec<f32>> {
+            self.samples.clone()
+        }
+    }
+
+    #[test]
+    fn raw_samples() {
+        let mut bytes = vec![];
+        for sample in [0.5f32, -1.0, 0.25].iter() {
+            let mut buffer = [0; 4];
+            LittleEndian::write_f32(&mut buffer, *sample);
+            bytes.extend(&buffer);
+        }
+        // a trailing partial sample is ignored
+        bytes.push(0);
+        let all = read_samples(&mut Cursor::new(bytes.clone()), None).unwrap();
+        assert_eq!(all, vec![0.5, -1.0, 0.25]);
+        let some = read_samples(&mut Cursor::new(bytes), Some(2)).unwrap();
+        assert_eq!(some, vec![0.5, -1.0]);
+    }
+
+    #[test]
+    fn falls_back() {
+        let decoders: Vec<Box<dyn Decoder>> = vec![
+            Box::new(FixedDecoder { samples: None }),
+            Box::new(FixedDecoder {
+                samples: Some(vec![1.0]),
+            }),
+        ];
+        let path = Path::new("track.m4p");
+        assert_eq!(decode_with(&decoders, path, None), Some(vec![1.0]));
+        assert_eq!(decode_with(&decoders[..1], path, None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_unicode_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // programs can't be given the path, but shouldn't panic
+        let path = Path::new(OsStr::from_bytes(b"track\xff.m4p"));
+        assert_eq!((FfmpegDecoder {}).decode(path, None), None);
+        assert_eq!((SoxDecoder {}).decode(path, None), None);
+    }
+}
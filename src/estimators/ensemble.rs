@@ -0,0 +1,191 @@
+/*
+    The ensemble "estimator". Rather than analysing audio, this combines the
+    tempos already calculated by other estimators (and read from tags) into
+    a single recommended tempo, using a weighted vote where tempos that are
+    an octave apart (half/double time) partially agree with each other.
+*/
+use library::ellingtondata::EllingtonData;
+use std::collections::BTreeMap;
+use types::*;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnsembleParams {
+    pub weights: BTreeMap<AlgorithmE, f64>, // the weight of each algorithm's vote
+    pub default_weight: f64,                // the weight of algorithms not listed in weights
+    pub tolerance: f64,                     // how close (relatively) two tempos must be to agree
+    pub octave_weight: f64,                 // how much a half/double tempo agrees, in [0, 1]
+    pub max_conflict: f64,                  // the share of conflicting votes that is a disagreement
+}
+
+impl Default for EnsembleParams {
+    fn default() -> EnsembleParams {
+        let mut weights = BTreeMap::new();
+        weights.insert(AlgorithmE::Actual, 3.0);
+        weights.insert(AlgorithmE::Bellson, 2.0);
+        weights.insert(AlgorithmE::Naive, 1.0);
+        EnsembleParams {
+            weights,
+            default_weight: 1.0,
+            tolerance: 0.04,
+            octave_weight: 0.5,
+            max_conflict: 0.25,
+        }
+    }
+}
+
+// A summary of how the votes were cast for an ensemble estimate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnsembleSummary {
+    pub disagreement: bool,          // whether the votes conflicted significantly
+    pub agreeing: Vec<AlgorithmE>,   // algorithms that voted for the chosen tempo
+    pub dissenting: Vec<AlgorithmE>, // algorithms that voted for another tempo
+}
+
+pub struct EnsembleEstimator {
+    pub params: EnsembleParams,
+}
+
+struct Vote {
+    algorithm: AlgorithmE,
+    bpm: f64,
+    weight: f64,
+}
+
+impl EnsembleEstimator {
+    pub fn new(params: &EnsembleParams) -> EnsembleEstimator {
+        EnsembleEstimator {
+            params: params.clone(),
+        }
+    }
+
+    fn agrees(&self, a: f64, b: f64) -> bool {
+        (a - b).abs() <= a.max(b) * self.params.tolerance
+    }
+
+    fn octave_agrees(&self, a: f64, b: f64) -> bool {
+        self.agrees(a * 2.0, b) || self.agrees(a, b * 2.0)
+    }
+
+    fn votes(&self, data: &EllingtonData) -> Vec<Vote> {
+        data.algs
+            .iter()
+            .filter(|(algorithm, _)| **algorithm != AlgorithmE::Ensemble)
+            .filter_map(|(algorithm, bpm)| match bpm.value() {
+                Some(b) if b > 0.0 => Some(Vote {
+                    algorithm: algorithm.clone(),
+                    bpm: b,
+                    weight: *self
+                        .params
+                        .weights
+                        .get(algorithm)
+                        .unwrap_or(&self.params.default_weight),
+                }),
+                _ => None,
+            })
+            .filter(|vote| vote.weight > 0.0)
+            .collect()
+    }
+
+    // The total (octave-aware) weight of the votes for a tempo
+    fn score(&self, votes: &[Vote], bpm: f64) -> f64 {
+        votes
+            .iter()
+            .map(|v| {
+                if self.agrees(v.bpm, bpm) {
+                    v.weight
+                } else if self.octave_agrees(v.bpm, bpm) {
+                    v.weight * self.params.octave_weight
+                } else {
+                    0.0
+                }
+            })
+            .sum()
+    }
+
+    #[flame("EnsembleEstimator")]
+    pub fn combine(&self, data: &EllingtonData) -> Option<(TempoEstimate, EnsembleSummary)> {
+        let votes = self.votes(data);
+        let total: f64 = votes.iter().map(|v| v.weight).sum();
+
+        // Each distinct tempo that was voted for is a candidate
+        let mut candidates: Vec<TempoCandidate> = vec![];
+        for vote in &votes {
+            if !candidates.iter().any(|c| self.agrees(c.bpm, vote.bpm)) {
+                candidates.push(TempoCandidate {
+                    bpm: vote.bpm,
+                    strength: self.score(&votes, vote.bpm),
+                });
+            }
+        }
+        let mut estimate = TempoEstimate::from_candidates(candidates)?;
+
+        // Refine the tempo by taking the weighted mean of the votes that agree with it.
+        let (agreeing, dissenting): (Vec<&Vote>, Vec<&Vote>) =
+            votes.iter().partition(|v| self.agrees(v.bpm, estimate.bpm));
+        let support: f64 = agreeing.iter().map(|v| v.weight).sum();
+        estimate.bpm = agreeing.iter().map(|v| v.bpm * v.weight).sum::<f64>() / support;
+        estimate.confidence = Some(support / total);
+
+        // Votes that are an octave away from the estimate are (probably) octave
+        // errors, anything else is a genuine conflict.
+        let conflict: f64 = dissenting
+            .iter()
+            .filter(|v| !self.octave_agrees(v.bpm, estimate.bpm))
+            .map(|v| v.weight)
+            .sum();
+
+        let summary = EnsembleSummary {
+            disagreement: conflict / total > self.params.max_conflict,
+            agreeing: agreeing.iter().map(|v| v.algorithm.clone()).collect(),
+            dissenting: dissenting.iter().map(|v| v.algorithm.clone()).collect(),
+        };
+        debug!("Ensemble estimate: {:?}, {:?}", estimate, summary);
+        Some((estimate, summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(values: Vec<(AlgorithmE, i64)>) -> EllingtonData {
+        let mut ed = EllingtonData::empty();
+        for (algorithm, bpm) in values {
+            ed.algs.insert(algorithm, BpmE::Bpm(bpm));
+        }
+        ed
+    }
+
+    #[test]
+    fn empty() {
+        let ensemble = EnsembleEstimator::new(&EnsembleParams::default());
+        assert!(ensemble.combine(&EllingtonData::empty()).is_none());
+    }
+
+    #[test]
+    fn octave_agreement() {
+        let ensemble = EnsembleEstimator::new(&EnsembleParams::default());
+        let (estimate, summary) = ensemble
+            .combine(&data(vec![
+                (AlgorithmE::Naive, 90),
+                (AlgorithmE::Bellson, 180),
+            ]))
+            .unwrap();
+        assert_eq!(estimate.to_bpm(), BpmE::Bpm(180));
+        assert_eq!(summary.dissenting, vec![AlgorithmE::Naive]);
+        assert!(!summary.disagreement);
+    }
+
+    #[test]
+    fn disagreement() {
+        let ensemble = EnsembleEstimator::new(&EnsembleParams::default());
+        let (estimate, summary) = ensemble
+            .combine(&data(vec![
+                (AlgorithmE::Naive, 120),
+                (AlgorithmE::Bellson, 150),
+            ]))
+            .unwrap();
+        assert_eq!(estimate.to_bpm(), BpmE::Bpm(150));
+        assert!(summary.disagreement);
+    }
+}
@@ -1,72 +1,240 @@
+pub mod combfilter;
+pub mod command;
+pub mod context;
+pub mod decoder;
+pub mod ensemble;
+pub mod octave;
+pub mod onset;
+pub mod registry;
+
+use library::trackmetadata::TrackMetadata;
 use regex::Regex;
+use serde_json;
 use shelltools::bellson::BellsonCommand;
 use shelltools::generic::ShellProgram;
-use std::path::PathBuf;
-use types::AlgorithmE;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use types::{MeterE, TempoEstimate};
+
+use self::command::TempoExtractor;
+use self::context::AnalysisContext;
+use self::octave::SwingPrior;
 
 use simple_bpm::*; 
-use hodges::*; 
+
+// The sample rate of the audio streams that we decode to.
+pub const SAMPLE_RATE: f64 = 44100.0;
+
+// Parameters used to configure an estimator when it is constructed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EstimatorParams {
+    pub min_bpm: f64,                 // the slowest tempo that we expect to see
+    pub max_bpm: f64,                 // the fastest tempo that we expect to see
+    pub accuracy: u32,                // the accuracy of the (naive) estimator
+    pub analysis_length: Option<f64>, // analyse (at most) this many seconds of audio
+    #[serde(default)]
+    pub octave_correction: Option<SwingPrior>, // correct octave errors, using this prior
+    #[serde(default)]
+    pub meter: Option<MeterE>, // the meter of the track, if we know it
+    #[serde(default)]
+    pub timeout: Option<f64>, // the most seconds to let a program (e.g. bellson) analyse a track for
+}
+
+impl Default for EstimatorParams {
+    fn default() -> EstimatorParams {
+        EstimatorParams {
+            min_bpm: 50.0, // set sensible defaults for swing music
+            max_bpm: 450.0,
+            accuracy: 2,
+            analysis_length: None,
+            octave_correction: None,
+            meter: None,
+            timeout: None,
+        }
+    }
+}
+
+impl EstimatorParams {
+    // Whether the tempos that we expect are a range that we can search, i.e.
+    // 0 < min_bpm < max_bpm
+    pub fn valid(&self) -> bool {
+        self.min_bpm > 0.0 && self.min_bpm < self.max_bpm && self.max_bpm.is_finite()
+    }
+
+    // Double or halve a tempo until it lies within [min_bpm, max_bpm], if possible.
+    pub fn fold(&self, bpm: f64) -> f64 {
+        let mut folded = bpm;
+        if folded <= 0.0 || self.min_bpm * 2.0 > self.max_bpm {
+            return folded;
+        }
+        while folded < self.min_bpm {
+            folded *= 2.0;
+        }
+        while folded > self.max_bpm {
+            folded /= 2.0;
+        }
+        folded
+    }
+
+    // A fingerprint of some of the parameters (by name), which changes
+    // whenever any of them do, but not when any of the others do.
+    pub fn fingerprint(&self, fields: &[&str]) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(map) = value.as_object_mut() {
+            map.retain(|key, _| fields.contains(&key.as_str()));
+        }
+        fingerprint(&value.to_string())
+    }
+
+    // The number of (mono) samples that an estimator should analyse.
+    pub fn analysis_samples(&self) -> Option<usize> {
+        self.analysis_length
+            .map(|seconds| (seconds.max(0.0) * SAMPLE_RATE) as usize)
+    }
+}
+
+// A short, stable (i.e. unlike std's hashers, the same across builds) hash of
+// some data, as hex. This is FNV-1a, which is plenty for spotting changes.
+pub fn fingerprint(data: &str) -> String {
+    let hash = data.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+// Decode (at most analysis_length seconds of) an audio file to a mono stream of samples.
+#[flame("Generic")]
+pub fn decode(audio_file: &Path, params: &EstimatorParams) -> Option<Vec<f32>> {
+    decoder::decode_with(&decoder::decoders(), audio_file, params.analysis_samples())
+}
 
 pub trait TempoEstimator {
-    const ALGORITHM: AlgorithmE;
-    fn run(audio_file: &PathBuf) -> Option<i64>;
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate>;
+
+    // Estimate the tempo of an already decoded (mono) stream of samples. Only
+    // estimators that analyse the audio themselves can do this.
+    fn run_samples(&self, _samples: &[f32]) -> Option<TempoEstimate> {
+        None
+    }
+
+    // Estimate the tempo of a track, sharing its decoded audio (and features)
+    // with any other estimators run over it. Estimators that don't analyse the
+    // audio themselves just run over the file.
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        self.run(&context.audio_file)
+    }
 }
 
-// todo: find a better way of doing this! I assume this is slow?
 #[flame("Generic")]
-pub fn run_estimator(name: &str, audio_file: &PathBuf) -> Option<(i64, &'static str)> {
-    match name {
-        "naive" => FfmpegNaiveTempoEstimator::run(audio_file)
-            .and_then(|bpm| Some((bpm, FfmpegNaiveTempoEstimator::ALGORITHM.print()))),
-        "bellson" => BellsonTempoEstimator::run(audio_file)
-            .and_then(|bpm| Some((bpm, BellsonTempoEstimator::ALGORITHM.print()))),
-        _ => {
+pub fn run_estimator(
+    name: &str,
+    params: &EstimatorParams,
+    audio_file: &PathBuf,
+) -> Option<(TempoEstimate, String)> {
+    match registry::lookup(name) {
+        Some(factory) => factory
+            .estimate(params, &AnalysisContext::new(audio_file, params))
+            .map(|estimate| (estimate, factory.algorithm().to_string())),
+        None => {
             error!("Could not find a tempo estimator of that name!");
             None
         }
     }
 }
 
-pub struct FfmpegNaiveTempoEstimator {}
+// "Estimates" the tempo of a track by reading the most trustworthy bpm stored
+// in the track's metadata
+pub struct TagTempoEstimator {}
+
+impl TagTempoEstimator {
+    fn estimate(metadata: TrackMetadata) -> Option<TempoEstimate> {
+        for conflict in metadata.tempo_conflicts() {
+            warn!(
+                "Tag {} has tempo {}, which conflicts with {:?}",
+                conflict.source,
+                conflict.bpm,
+                metadata.tag_tempo()
+            );
+        }
+        metadata
+            .tag_tempo()
+            .map(|tempo| TempoEstimate::from_bpm(tempo.bpm, None))
+    }
+}
+
+impl TempoEstimator for TagTempoEstimator {
+    #[flame("TagTempoEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        Self::estimate(TrackMetadata::from_file(audio_file.as_path())?)
+    }
+
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        Self::estimate(context.metadata()?)
+    }
+}
+
+pub struct FfmpegNaiveTempoEstimator {
+    pub params: EstimatorParams,
+}
+
+impl FfmpegNaiveTempoEstimator {
+    pub fn new(params: &EstimatorParams) -> FfmpegNaiveTempoEstimator {
+        FfmpegNaiveTempoEstimator {
+            params: params.clone(),
+        }
+    }
+}
 
 impl TempoEstimator for FfmpegNaiveTempoEstimator {
-    const ALGORITHM: AlgorithmE = AlgorithmE::Naive;
     #[flame("FfmpegNaiveTempoEstimator")]
-    fn run(audio_file: &PathBuf) -> Option<i64> {
-        let mut estimator = SimpleEstimator::with_accuracy(2); 
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        self.run_context(&AnalysisContext::new(audio_file, &self.params))
+    }
 
-        let state: State<&[f32]> =
-        State::from_file(audio_file.clone())?;
+    #[flame("FfmpegNaiveTempoEstimator")]
+    fn run_samples(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        // The simple estimator has no notion of a tempo range, so fold its
+        // result into the range that we've been configured with.
+        let mut estimator = SimpleEstimator::with_accuracy(self.params.accuracy);
+        let bpm = estimator.analyse(samples.iter().cloned());
+        Some(TempoEstimate::from_bpm(self.params.fold(bpm as f64), None))
+    }
 
-        Some(estimator.analyse(state.flatten().cloned()) as i64)
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        self.run_samples(&context.samples()?)
     }
 }
 
-pub struct BellsonTempoEstimator {}
+pub struct BellsonTempoEstimator {
+    pub timeout: Option<f64>, // seconds, or bellson's default
+}
+
+impl BellsonTempoEstimator {
+    pub fn new(params: &EstimatorParams) -> BellsonTempoEstimator {
+        BellsonTempoEstimator {
+            timeout: params.timeout,
+        }
+    }
+}
 
 impl TempoEstimator for BellsonTempoEstimator {
-    const ALGORITHM: AlgorithmE = AlgorithmE::Bellson;
     #[flame("BellsonTempoEstimator")]
-    fn run(audio_file: &PathBuf) -> Option<i64> {
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"Mean: (\d+)").unwrap();
+            static ref EXTRACTOR: TempoExtractor =
+                TempoExtractor::Regex(Regex::new(r"Mean: (\d+(?:\.\d+)?)").unwrap());
+        }
+        let mut call = BellsonCommand::default(audio_file);
+        if let Some(timeout) = self.timeout {
+            call.timeout = Duration::from_millis((timeout.max(0.0) * 1000.0) as u64);
         }
-        let call = BellsonCommand::default(audio_file);
         match call.run() {
             Some((stdout, _stderr)) => {
-                let captures = RE.captures(stdout.as_str())?;
-
-                debug!("Captures: {:?}", captures);
-
-                let bpm = captures.get(1)?.as_str();
-
-                debug!("BPM: {:?}", bpm);
-
-                let bpm = bpm.parse::<i64>().ok()?;
+                let bpm = EXTRACTOR.extract(stdout.as_str())?;
 
-                debug!("bpm<i64>: {:?}", bpm);
+                debug!("bpm<f64>: {:?}", bpm);
 
-                Some(bpm)
+                Some(TempoEstimate::from_bpm(bpm, None))
             }
             _ => {
                 error!("Got error while running bellson!");
@@ -0,0 +1,211 @@
+/*
+    Octave error correction. Most tempo estimators are prone to reporting
+    half, or double the "true" tempo of a track. This stage runs after an
+    estimator, and uses a prior over the tempos of swing music, along with
+    the strengths of the candidates the estimator found, to decide whether
+    the estimate should be halved or doubled.
+
+    In triple meter (waltzes), estimators are also prone to "dotted" errors
+    of 3/2 or 2/3 of the true tempo, or to reporting the tempo of the bars,
+    so if we know that a track is in triple meter we consider those too.
+*/
+use estimators::context::AnalysisContext;
+use estimators::TempoEstimator;
+use std::path::PathBuf;
+use types::*;
+
+// A (log-normal) prior over the tempos that we expect to see in swing music.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SwingPrior {
+    pub centre_bpm: f64, // the most likely tempo
+    pub width: f64,      // the standard deviation of the prior, in octaves
+    pub min_bpm: f64,    // tempos outside [min_bpm, max_bpm] are impossible
+    pub max_bpm: f64,
+}
+
+impl Default for SwingPrior {
+    fn default() -> SwingPrior {
+        SwingPrior {
+            centre_bpm: 160.0,
+            width: 0.5,
+            min_bpm: 50.0,
+            max_bpm: 450.0,
+        }
+    }
+}
+
+impl SwingPrior {
+    // The (unnormalised) likelihood of a given tempo
+    pub fn weight(&self, bpm: f64) -> f64 {
+        if bpm < self.min_bpm || bpm > self.max_bpm || self.width <= 0.0 {
+            return 0.0;
+        }
+        let octaves = (bpm / self.centre_bpm).log2() / self.width;
+        (-0.5 * octaves * octaves).exp()
+    }
+}
+
+pub struct OctaveCorrector {
+    pub prior: SwingPrior,
+    pub meter: Option<MeterE>,
+}
+
+impl OctaveCorrector {
+    // The multiples of the estimated tempo that we consider, the first is preferred on ties
+    const FACTORS: [f64; 3] = [1.0, 0.5, 2.0];
+    const TRIPLE_FACTORS: [f64; 7] = [1.0, 0.5, 2.0, 1.5, 2.0 / 3.0, 3.0, 1.0 / 3.0];
+    // How close (relatively) a candidate must be to a tempo to support it
+    const TOLERANCE: f64 = 0.04;
+    // The support given to other tempos, when an estimator only reports one candidate
+    const UNSUPPORTED: f64 = 0.5;
+
+    pub fn new(prior: &SwingPrior) -> OctaveCorrector {
+        OctaveCorrector {
+            prior: prior.clone(),
+            meter: None,
+        }
+    }
+
+    // Also correct errors specific to the meter of the track, if we know it.
+    pub fn with_meter(mut self, meter: Option<MeterE>) -> OctaveCorrector {
+        self.meter = meter;
+        self
+    }
+
+    fn factors(&self) -> &'static [f64] {
+        match self.meter {
+            Some(MeterE::Triple) => &Self::TRIPLE_FACTORS,
+            _ => &Self::FACTORS,
+        }
+    }
+
+    // How strongly the candidates of an estimate support a given tempo, in [0, 1]
+    fn support(estimate: &TempoEstimate, bpm: f64) -> f64 {
+        let strongest = estimate
+            .candidates
+            .iter()
+            .map(|c| c.strength)
+            .fold(0.0, f64::max);
+        if estimate.candidates.len() < 2 || strongest <= 0.0 {
+            // Without any information from the estimator, trust the estimate itself.
+            return if bpm == estimate.bpm {
+                1.0
+            } else {
+                Self::UNSUPPORTED
+            };
+        }
+        estimate
+            .candidates
+            .iter()
+            .filter(|c| (c.bpm - bpm).abs() <= bpm * Self::TOLERANCE)
+            .map(|c| c.strength / strongest)
+            .fold(0.0, f64::max)
+    }
+
+    pub fn correct(&self, mut estimate: TempoEstimate) -> TempoEstimate {
+        let original = estimate.bpm;
+        let mut best = (1.0, 0.0);
+        for factor in self.factors() {
+            let bpm = original * factor;
+            let score = self.prior.weight(bpm) * Self::support(&estimate, bpm);
+            debug!(
+                "Octave correction: {} x {} scores {}",
+                original, factor, score
+            );
+            if score > best.1 {
+                best = (*factor, score);
+            }
+        }
+        let (factor, _) = best;
+        if factor != 1.0 {
+            info!(
+                "Correcting tempo error: {} -> {}",
+                original,
+                original * factor
+            );
+        }
+        estimate.bpm = original * factor;
+        estimate.correction = Some(OctaveCorrection {
+            original_bpm: original,
+            factor,
+        });
+        estimate
+    }
+}
+
+// Wraps an estimator, and corrects the octave of its results.
+pub struct OctaveCorrectedEstimator {
+    pub inner: Box<dyn TempoEstimator>,
+    pub corrector: OctaveCorrector,
+}
+
+impl TempoEstimator for OctaveCorrectedEstimator {
+    #[flame("OctaveCorrectedEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        self.inner
+            .run(audio_file)
+            .map(|estimate| self.corrector.correct(estimate))
+    }
+
+    fn run_samples(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        self.inner
+            .run_samples(samples)
+            .map(|estimate| self.corrector.correct(estimate))
+    }
+
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        self.inner
+            .run_context(context)
+            .map(|estimate| self.corrector.correct(estimate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(bpm: f64, strength: f64) -> TempoCandidate {
+        TempoCandidate { bpm, strength }
+    }
+
+    #[test]
+    fn doubles_slow_estimate() {
+        let corrector = OctaveCorrector::new(&SwingPrior::default());
+        let estimate = corrector.correct(TempoEstimate::from_bpm(90.0, None));
+        assert_eq!(estimate.bpm, 180.0);
+        assert!(estimate.correction.unwrap().applied());
+    }
+
+    #[test]
+    fn keeps_plausible_estimate() {
+        let corrector = OctaveCorrector::new(&SwingPrior::default());
+        let estimate = corrector.correct(TempoEstimate::from_bpm(160.0, None));
+        assert_eq!(estimate.bpm, 160.0);
+        assert!(!estimate.correction.unwrap().applied());
+    }
+
+    #[test]
+    fn respects_candidate_strengths() {
+        let corrector = OctaveCorrector::new(&SwingPrior::default());
+        // a strong slow candidate, with only a very weak double-time peak
+        let estimate =
+            TempoEstimate::from_candidates(vec![candidate(100.0, 1.0), candidate(200.0, 0.05)])
+                .unwrap();
+        assert_eq!(corrector.correct(estimate).bpm, 100.0);
+    }
+
+    #[test]
+    fn corrects_dotted_waltz() {
+        // an estimator that heard a waltz at 150bpm as 100bpm
+        let estimate = TempoEstimate::from_candidates(vec![
+            candidate(100.0, 1.0),
+            candidate(150.0, 0.8),
+            candidate(50.0, 0.3),
+        ])
+        .unwrap();
+        let corrector = OctaveCorrector::new(&SwingPrior::default());
+        assert_eq!(corrector.correct(estimate.clone()).bpm, 100.0);
+        let corrector = corrector.with_meter(Some(MeterE::Triple));
+        assert_eq!(corrector.correct(estimate).bpm, 150.0);
+    }
+}
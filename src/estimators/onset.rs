@@ -0,0 +1,104 @@
+/*
+    A pure rust tempo estimator. Audio is decoded (by hodges, or ffmpeg), reduced to
+    a spectral flux onset envelope, and the tempo is chosen from the peaks
+    of the autocorrelation of the envelope.
+*/
+use dsp::onset::OnsetEnvelope;
+use dsp::*;
+use estimators::context::AnalysisContext;
+use estimators::*;
+use std::path::PathBuf;
+use types::*;
+
+pub struct OnsetTempoEstimator {
+    pub params: EstimatorParams,
+}
+
+impl OnsetTempoEstimator {
+    pub fn new(params: &EstimatorParams) -> OnsetTempoEstimator {
+        OnsetTempoEstimator {
+            params: params.clone(),
+        }
+    }
+
+    // Estimate a tempo from the peaks of the autocorrelation of an onset envelope
+    pub fn estimate(&self, envelope: &OnsetEnvelope) -> Option<TempoEstimate> {
+        let (min_lag, max_lag) = envelope
+            .lags(self.params.min_bpm, self.params.max_bpm)
+            .or_else(|| {
+                error!(
+                    "Cannot search for tempos between {} and {} bpm",
+                    self.params.min_bpm, self.params.max_bpm
+                );
+                None
+            })?;
+        let acf = autocorrelation(&envelope.values, max_lag + 1);
+
+        let candidates: Vec<TempoCandidate> = peaks(&acf, min_lag, max_lag + 1)
+            .into_iter()
+            .filter(|(_, strength)| *strength > 0.0)
+            .map(|(lag, strength)| TempoCandidate {
+                bpm: envelope.bpm(lag),
+                strength,
+            })
+            .collect();
+        debug!("Autocorrelation candidates: {:?}", candidates);
+
+        TempoEstimate::from_candidates(candidates)
+    }
+
+    // Estimate the tempo of a (mono) stream of samples
+    pub fn analyse(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        self.estimate(&OnsetEnvelope::spectral_flux(samples, SAMPLE_RATE))
+    }
+}
+
+impl TempoEstimator for OnsetTempoEstimator {
+    #[flame("OnsetTempoEstimator")]
+    fn run(&self, audio_file: &PathBuf) -> Option<TempoEstimate> {
+        self.run_context(&AnalysisContext::new(audio_file, &self.params))
+    }
+
+    fn run_samples(&self, samples: &[f32]) -> Option<TempoEstimate> {
+        self.analyse(samples)
+    }
+
+    fn run_context(&self, context: &AnalysisContext) -> Option<TempoEstimate> {
+        self.estimate(&*context.envelope()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth;
+
+    #[test]
+    fn clicks() {
+        let estimator = OnsetTempoEstimator::new(&EstimatorParams::default());
+        for bpm in [100.0, 120.0, 185.0].iter() {
+            let estimate = estimator
+                .analyse(&synth::render(&synth::clicks(*bpm, 20.0), 20.0))
+                .unwrap();
+            assert!(
+                (estimate.bpm - bpm).abs() < 2.0,
+                "Estimated {} for a click track at {}",
+                estimate.bpm,
+                bpm
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_tempo_range() {
+        let samples = synth::render(&synth::clicks(120.0, 5.0), 5.0);
+        for (min_bpm, max_bpm) in [(0.0, 450.0), (-60.0, 450.0), (200.0, 100.0)].iter() {
+            let estimator = OnsetTempoEstimator::new(&EstimatorParams {
+                min_bpm: *min_bpm,
+                max_bpm: *max_bpm,
+                ..EstimatorParams::default()
+            });
+            assert!(estimator.analyse(&samples).is_none());
+        }
+    }
+}
@@ -0,0 +1,332 @@
+/*
+    A runtime registry of tempo estimators. Estimators are registered as
+    factories (a name, a short code, and a constructor), so that the CLI and
+    the library can dispatch on whatever is registered, rather than on a
+    hard-coded list of algorithms.
+*/
+use estimators::combfilter::CombFilterTempoEstimator;
+use estimators::context::AnalysisContext;
+use estimators::octave::*;
+use estimators::onset::OnsetTempoEstimator;
+use estimators::*;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use types::*;
+
+pub trait EstimatorFactory: Send + Sync {
+    // the (full) name of the estimator, as given on the command line
+    fn name(&self) -> &str;
+    // the short code of the estimator, used in minimal metadata
+    fn short_code(&self) -> &str;
+    // the key under which results of the estimator are stored
+    fn algorithm(&self) -> AlgorithmE;
+    // the version of the estimator, which should change whenever its results might
+    fn version(&self) -> &str;
+    // construct a new instance of the estimator, configured with some parameters
+    fn construct(&self, params: &EstimatorParams) -> Box<dyn TempoEstimator>;
+    // whether post-processing stages may change the results of the estimator
+    fn correctable(&self) -> bool {
+        true
+    }
+    // the parameters (by name) that the estimator reads when it is constructed
+    fn reads(&self) -> &[&str] {
+        &[]
+    }
+
+    // a fingerprint of the parameters that the estimator, and any post-processing
+    // stages, read - so that changing any other parameter leaves its results current
+    fn fingerprint(&self, params: &EstimatorParams) -> String {
+        let mut fields = self.reads().to_vec();
+        if self.correctable() && params.octave_correction.is_some() {
+            fields.extend_from_slice(&["octave_correction", "meter"]);
+        }
+        params.fingerprint(&fields)
+    }
+
+    // construct the estimator, along with any post-processing stages requested
+    fn build(&self, params: &EstimatorParams) -> Box<dyn TempoEstimator> {
+        let estimator = self.construct(params);
+        match &params.octave_correction {
+            Some(prior) if self.correctable() => Box::new(OctaveCorrectedEstimator {
+                inner: estimator,
+                corrector: OctaveCorrector::new(prior).with_meter(params.meter),
+            }),
+            _ => estimator,
+        }
+    }
+
+    // where the results of the estimator, built with some parameters, come from
+    fn provenance(&self, params: &EstimatorParams) -> Provenance {
+        Provenance {
+            version: self.version().to_string(),
+            params: self.fingerprint(params),
+        }
+    }
+
+    // build the estimator and run it over a track, recording the provenance of the result
+    fn estimate(
+        &self,
+        params: &EstimatorParams,
+        context: &AnalysisContext,
+    ) -> Option<TempoEstimate> {
+        let mut estimate = self.build(params).run_context(context)?;
+        estimate.provenance = Some(self.provenance(params));
+        estimate.estimated_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|t| t.as_secs());
+        Some(estimate)
+    }
+}
+
+// A factory for estimators that are compiled into ellington itself.
+pub struct BuiltinEstimator {
+    pub name: &'static str,
+    pub short_code: &'static str,
+    pub algorithm: AlgorithmE,
+    pub constructor: fn(&EstimatorParams) -> Box<dyn TempoEstimator>,
+    pub version: &'static str, // bump this when the estimator (or a library that it uses) changes
+    pub correctable: bool,
+    pub reads: &'static [&'static str], // the parameters that the constructor reads
+}
+
+impl EstimatorFactory for BuiltinEstimator {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn short_code(&self) -> &str {
+        self.short_code
+    }
+
+    fn algorithm(&self) -> AlgorithmE {
+        self.algorithm.clone()
+    }
+
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn construct(&self, params: &EstimatorParams) -> Box<dyn TempoEstimator> {
+        (self.constructor)(params)
+    }
+
+    fn correctable(&self) -> bool {
+        self.correctable
+    }
+
+    fn reads(&self) -> &[&str] {
+        self.reads
+    }
+}
+
+// The estimators that are run when "all" of them are asked for: those that
+// ellington has always run. Other estimators, and the ensemble, must be asked
+// for by name.
+pub const DEFAULT_ESTIMATORS: &[&str] = &["actual", "bellson", "naive"];
+
+#[derive(Clone)]
+pub struct Registry {
+    factories: Vec<Arc<dyn EstimatorFactory>>,
+}
+
+impl Registry {
+    pub fn empty() -> Registry {
+        Registry { factories: vec![] }
+    }
+
+    // The registry of estimators that ship with ellington.
+    pub fn builtin() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register(BuiltinEstimator {
+            name: "actual",
+            short_code: "a",
+            algorithm: AlgorithmE::Actual,
+            constructor: |_| Box::new(TagTempoEstimator {}),
+            version: "2",
+            // tags are set by the user, so should never be corrected
+            correctable: false,
+            reads: &[],
+        });
+        registry.register(BuiltinEstimator {
+            name: "naive",
+            short_code: "n",
+            algorithm: AlgorithmE::Naive,
+            constructor: |params| Box::new(FfmpegNaiveTempoEstimator::new(params)),
+            version: "1",
+            correctable: true,
+            reads: &["min_bpm", "max_bpm", "accuracy", "analysis_length"],
+        });
+        registry.register(BuiltinEstimator {
+            name: "bellson",
+            short_code: "b",
+            algorithm: AlgorithmE::Bellson,
+            constructor: |params| Box::new(BellsonTempoEstimator::new(params)),
+            version: "1",
+            correctable: true,
+            reads: &[],
+        });
+        registry.register(BuiltinEstimator {
+            name: "onset",
+            short_code: "o",
+            algorithm: AlgorithmE::Onset,
+            constructor: |params| Box::new(OnsetTempoEstimator::new(params)),
+            version: "1",
+            correctable: true,
+            reads: &["min_bpm", "max_bpm", "analysis_length"],
+        });
+        registry.register(BuiltinEstimator {
+            name: "comb",
+            short_code: "c",
+            algorithm: AlgorithmE::Comb,
+            constructor: |params| Box::new(CombFilterTempoEstimator::new(params)),
+            version: "1",
+            correctable: true,
+            reads: &["min_bpm", "max_bpm", "analysis_length"],
+        });
+        registry
+    }
+
+    // Register a new estimator, replacing any existing estimator of the same name.
+    pub fn register<F: EstimatorFactory + 'static>(&mut self, factory: F) {
+        self.factories.retain(|f| f.name() != factory.name());
+        self.factories.push(Arc::new(factory));
+    }
+
+    // Find an estimator from either its name, or its short code.
+    pub fn lookup(&self, name: &str) -> Option<Arc<dyn EstimatorFactory>> {
+        self.factories
+            .iter()
+            .find(|f| f.name() == name)
+            .or_else(|| self.factories.iter().find(|f| f.short_code() == name))
+            .cloned()
+    }
+
+    pub fn factories(&self) -> Vec<Arc<dyn EstimatorFactory>> {
+        self.factories.clone()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.factories
+            .iter()
+            .map(|f| f.name().to_string())
+            .collect()
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Registry> = RwLock::new(Registry::builtin());
+}
+
+// Register an estimator with the global registry.
+pub fn register<F: EstimatorFactory + 'static>(factory: F) {
+    REGISTRY.write().unwrap().register(factory);
+}
+
+// Take a snapshot of the global registry.
+pub fn registry() -> Registry {
+    REGISTRY.read().unwrap().clone()
+}
+
+// Find an estimator in the global registry.
+pub fn lookup(name: &str) -> Option<Arc<dyn EstimatorFactory>> {
+    REGISTRY.read().unwrap().lookup(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ConstantTempoEstimator {}
+
+    impl TempoEstimator for ConstantTempoEstimator {
+        fn run(&self, _audio_file: &PathBuf) -> Option<TempoEstimate> {
+            Some(TempoEstimate::from_bpm(180.0, None))
+        }
+    }
+
+    #[test]
+    fn lookup_by_name_and_short_code() {
+        let registry = Registry::builtin();
+        assert_eq!(
+            registry.lookup("naive").unwrap().algorithm(),
+            AlgorithmE::Naive
+        );
+        assert_eq!(
+            registry.lookup("b").unwrap().algorithm(),
+            AlgorithmE::Bellson
+        );
+        assert!(registry.lookup("nonexistent").is_none());
+        for name in DEFAULT_ESTIMATORS {
+            assert!(registry.lookup(name).is_some());
+        }
+    }
+
+    #[test]
+    fn register_replaces_by_name() {
+        let mut registry = Registry::builtin();
+        let count = registry.names().len();
+        registry.register(BuiltinEstimator {
+            name: "naive",
+            short_code: "n",
+            algorithm: AlgorithmE::Naive,
+            constructor: |_| Box::new(ConstantTempoEstimator {}),
+            version: "1",
+            correctable: true,
+            reads: &[],
+        });
+        assert_eq!(registry.names().len(), count);
+        let estimator = registry
+            .lookup("naive")
+            .unwrap()
+            .construct(&EstimatorParams::default());
+        let estimate = estimator.run(&PathBuf::from("track.mp3")).unwrap();
+        assert_eq!(estimate.to_bpm(), BpmE::Bpm(180));
+    }
+
+    #[test]
+    fn provenance() {
+        let factory = BuiltinEstimator {
+            name: "constant",
+            short_code: "k",
+            algorithm: AlgorithmE::Custom("constant".to_string()),
+            constructor: |_| Box::new(ConstantTempoEstimator {}),
+            version: "2",
+            correctable: true,
+            reads: &["max_bpm"],
+        };
+        let params = EstimatorParams::default();
+        let context = AnalysisContext::new(&PathBuf::from("track.mp3"), &params);
+        let estimate = factory.estimate(&params, &context).unwrap();
+        assert!(estimate.is_current(&factory.provenance(&params)));
+
+        let slower = EstimatorParams {
+            max_bpm: 300.0,
+            ..params.clone()
+        };
+        assert!(!estimate.is_current(&factory.provenance(&slower)));
+
+        // parameters that the estimator doesn't read don't make it stale...
+        let unread = EstimatorParams {
+            accuracy: 5,
+            meter: Some(MeterE::Triple),
+            ..params.clone()
+        };
+        assert!(estimate.is_current(&factory.provenance(&unread)));
+        // ...unless a post-processing stage reads them
+        let corrected = EstimatorParams {
+            octave_correction: Some(SwingPrior::default()),
+            ..params.clone()
+        };
+        assert!(!estimate.is_current(&factory.provenance(&corrected)));
+        let triple = EstimatorParams {
+            meter: Some(MeterE::Triple),
+            ..corrected.clone()
+        };
+        assert_ne!(
+            factory.fingerprint(&corrected),
+            factory.fingerprint(&triple)
+        );
+        assert!(!TempoEstimate::from_bpm(180.0, None).is_current(&factory.provenance(&params)));
+    }
+}
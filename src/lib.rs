@@ -10,6 +10,8 @@ extern crate env_logger;
 extern crate nom;
 
 extern crate byteorder;
+#[cfg(unix)]
+extern crate libc;
 
 extern crate percent_encoding;
 extern crate plist;
@@ -32,7 +34,10 @@ extern crate flame;
 #[macro_use]
 extern crate flamer;
 
+pub mod analysis;
+pub mod dsp;
 pub mod estimators;
 pub mod library;
 pub mod shelltools;
+pub mod synth;
 pub mod types;
@@ -1,7 +1,11 @@
+use analysis::swing::SwingRatio;
+use estimators::ensemble::*;
 use nom;
+use nom::types::CompleteStr;
 use regex::Regex;
 use serde_json;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::ops;
 use types::*;
 
@@ -15,55 +19,332 @@ pub enum UpdateError {
 }
 pub type UpdateResult<T> = Result<T, UpdateError>;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+// Why ellington data in a comment couldn't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseReason {
+    NoData,                                       // there's no ellington data in the comment
+    Version(String),                              // the data has a version that we don't know
+    Checksum { expected: String, found: String }, // the data has been changed
+    Syntax(String),                               // a value couldn't be read
+    Meter(String),                                // a meter isn't 2, 3 or 4
+}
+
+impl fmt::Display for ParseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseReason::NoData => write!(f, "no ellington data found"),
+            ParseReason::Version(data) => write!(f, "unknown version of ellington data {:?}", data),
+            ParseReason::Checksum { expected, found } => {
+                write!(
+                    f,
+                    "checksum {} does not match data (expected {})",
+                    found, expected
+                )
+            }
+            ParseReason::Syntax(value) => write!(f, "could not read value {:?}", value),
+            ParseReason::Meter(meter) => write!(f, "unknown meter {:?}", meter),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub offset: usize, // the offset (in bytes) into the comment of the problem
+    pub reason: ParseReason,
+}
+
+impl ParseError {
+    pub fn new(offset: usize, reason: ParseReason) -> ParseError {
+        ParseError { offset, reason }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at byte {}, {}", self.offset, self.reason)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseMode {
+    Strict,  // reject data with any errors
+    Lenient, // keep whatever values can be read
+}
+
+// The versions of the format that ellington data is written to comments in:
+//  v1: [ed| actual~120, naive~142.5 |]
+//  v2: [ed2| actual~120:verified, naive~142.5:confidence=0.83:version=1; meter=4 |3f0a]
+// where v2 adds (optional) attributes to each tempo, fields of the track (after
+// the tempos, so that they can't be mistaken for algorithms), and a checksum
+// (four hex digits of a hash of everything between the bars, see checksum).
+// Minimal v2 data uses short names, e.g. "a~120:u,n~142.5:c=0.83:v=1;m=4".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatVersion {
+    V1,
+    V2,
+}
+
+impl FormatVersion {
+    pub fn parse(st: &str) -> Option<FormatVersion> {
+        match st {
+            "1" => Some(FormatVersion::V1),
+            "2" => Some(FormatVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+// How to write ellington data to a comment
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatOptions {
+    pub minimal: bool,          // use short codes, and no spaces
+    pub precision: Option<u32>, // the most decimal places to write tempos to, if limited
+    pub version: FormatVersion,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            minimal: false,
+            precision: None,
+            version: FormatVersion::V1,
+        }
+    }
+}
+
+// What we know about a tempo other than its value, written to v2 comments
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ValueAttributes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>, // how confident the estimator was, in [0, 1]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>, // the version of the estimator
+    #[serde(default)]
+    pub verified: bool, // whether a user has checked the tempo
+}
+
+impl ValueAttributes {
+    // Versions are written as is, so can only use a few characters
+    fn is_value_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '.' || c == '-' || c == '_'
+    }
+
+    fn format(&self, minimal: bool) -> String {
+        let key = |name: &'static str, short: &'static str| if minimal { short } else { name };
+        let mut s = String::new();
+        if let Some(c) = self.confidence {
+            s.push_str(&format!(":{}={:.2}", key("confidence", "c"), c));
+        }
+        match &self.version {
+            Some(v) if v.chars().all(Self::is_value_char) => {
+                s.push_str(&format!(":{}={}", key("version", "v"), v))
+            }
+            Some(v) => info!("Not writing estimator version {:?}, it isn't compact", v),
+            None => {}
+        }
+        if self.verified {
+            s.push_str(&format!(":{}", key("verified", "u")));
+        }
+        s
+    }
+
+    fn parse(attributes: &[&str]) -> ValueAttributes {
+        let mut parsed = ValueAttributes::default();
+        for attribute in attributes {
+            let mut parts = attribute.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("c"), Some(c)) | (Some("confidence"), Some(c)) => {
+                    parsed.confidence = c.parse().ok()
+                }
+                (Some("v"), Some(v)) | (Some("version"), Some(v)) => {
+                    parsed.version = Some(v.to_string())
+                }
+                (Some("u"), None) | (Some("verified"), None) => parsed.verified = true,
+                _ => warn!(
+                    "Ignoring unknown attribute {:?} in ellington data",
+                    attribute
+                ),
+            }
+        }
+        parsed
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct EllingtonData {
     pub algs: BTreeMap<Algorithm, Bpm>,
+    // The full estimates that produced (some of) the tempos in algs. These are
+    // only stored in the library, and are never written to comments.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub estimates: BTreeMap<Algorithm, TempoEstimate>,
+    // How the ensemble tempo (if any) was voted for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ensemble: Option<EnsembleSummary>,
+    // How hard the track swings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swing: Option<SwingRatio>,
+    // The meter of the track, this is written to (v2) comments after the tempos.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meter: Option<MeterE>,
+    // Attributes of (some of) the tempos in algs, read from v2 comments
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<Algorithm, ValueAttributes>,
 }
 
 impl EllingtonData {
+    // The field of v2 comments that the meter is written to
+    const METER: &'static str = "meter";
+
     pub fn empty() -> EllingtonData {
+        EllingtonData::with_algs(BTreeMap::new())
+    }
+
+    pub fn with_algs(algs: BTreeMap<Algorithm, Bpm>) -> EllingtonData {
         EllingtonData {
-            algs: BTreeMap::new(),
+            algs,
+            estimates: BTreeMap::new(),
+            ensemble: None,
+            swing: None,
+            meter: None,
+            attributes: BTreeMap::new(),
         }
     }
 
     pub fn with_algorithm(a: Algorithm, b: Bpm) -> EllingtonData {
         let mut map = BTreeMap::new();
         map.insert(a, b);
-        EllingtonData { algs: map }
+        EllingtonData::with_algs(map)
+    }
+
+    // Combine the tempos that we have into an ensemble tempo, and record it.
+    pub fn insert_ensemble(self: &mut Self, ensemble: &EnsembleEstimator) -> Option<()> {
+        let (estimate, summary) = ensemble.combine(self)?;
+        self.insert_estimate(AlgorithmE::Ensemble, estimate);
+        self.ensemble = Some(summary);
+        Some(())
+    }
+
+    // The best tempo that we know, preferring tagged tempos, then the
+    // ensemble of the estimators.
+    pub fn known_bpm(&self) -> Option<f64> {
+        [AlgorithmE::Actual, AlgorithmE::Ensemble]
+            .iter()
+            .filter_map(|a| match (self.estimates.get(a), self.algs.get(a)) {
+                (Some(estimate), _) => Some(estimate.bpm),
+                (None, Some(bpm)) => bpm.value().filter(|b| *b > 0.0),
+                _ => None,
+            })
+            .next()
+    }
+
+    // Record an estimate, along with the tempo that it gives.
+    pub fn insert_estimate(self: &mut Self, a: Algorithm, estimate: TempoEstimate) {
+        self.algs.insert(a.clone(), estimate.to_bpm());
+        // anything that we knew about the previous tempo no longer holds
+        self.attributes.remove(&a);
+        self.estimates.insert(a, estimate);
+    }
+
+    // The attributes of the tempo of an algorithm, including those of its estimate
+    pub fn value_attributes(&self, algorithm: &Algorithm) -> ValueAttributes {
+        let attributes = self.attributes.get(algorithm).cloned().unwrap_or_default();
+        match self.estimates.get(algorithm) {
+            Some(estimate) => ValueAttributes {
+                confidence: estimate.confidence.or(attributes.confidence),
+                version: match &estimate.provenance {
+                    Some(p) => Some(p.version.clone()),
+                    None => attributes.version,
+                },
+                ..attributes
+            },
+            None => attributes,
+        }
     }
 
-    #[flame]
     pub fn format(self: &Self, minimal: bool) -> UpdateResult<String> {
-        let mut s = String::new();
-        s.push_str("[ed|");
+        self.format_with(&FormatOptions {
+            minimal,
+            ..FormatOptions::default()
+        })
+    }
+
+    // The tempo to write for an algorithm, to (at most) precision decimal
+    // places if given. We round the full estimate where we have one, as the
+    // tempo in algs may already have been rounded.
+    fn tempo(&self, algorithm: &Algorithm, bpm: &Bpm, precision: Option<u32>) -> Bpm {
+        match (precision, self.estimates.get(algorithm)) {
+            (Some(places), Some(estimate)) => BpmE::from_f64(estimate.bpm, places),
+            (Some(places), None) => bpm.round(places),
+            (None, _) => bpm.clone(),
+        }
+    }
 
-        let mut first = true;
+    #[flame]
+    pub fn format_with(self: &Self, options: &FormatOptions) -> UpdateResult<String> {
+        let minimal = options.minimal;
+        let mut values = vec![];
 
         for (algorithm, bpm) in self.algs.iter() {
-            if first {
-                first = false;
+            let bpm = self.tempo(algorithm, bpm, options.precision);
+            let name = if minimal {
+                algorithm.short_code().to_string()
             } else {
-                s.push_str(",");
-            }
-            if minimal {
-                s.push_str(&format!(
-                    "{}~{}",
-                    algorithm.print().chars().next().unwrap(),
-                    bpm
-                ));
+                algorithm.print().to_string()
+            };
+            let attributes = match options.version {
+                FormatVersion::V1 => String::new(),
+                FormatVersion::V2 => self.value_attributes(algorithm).format(minimal),
+            };
+            values.push(format!("{}~{}{}", name, bpm, attributes));
+        }
+
+        // v1 data has no fields, as older versions of ellington would read
+        // them as tempos
+        let mut fields = vec![];
+        if let (Some(meter), FormatVersion::V2) = (&self.meter, options.version) {
+            let name = if minimal {
+                &Self::METER[..1]
             } else {
-                s.push_str(&format!(" {}~{}", algorithm.print(), bpm));
-            }
+                Self::METER
+            };
+            fields.push(format!("{}={}", name, meter));
         }
 
-        if minimal {
-            s.push_str("|]");
+        let body = if minimal {
+            let mut body = values.join(",");
+            if !fields.is_empty() {
+                body = body + ";" + &fields.join(",");
+            }
+            body
         } else {
-            s.push_str(" |]");
-        }
+            let spaced = |items: &[String]| {
+                items
+                    .iter()
+                    .map(|i| format!(" {}", i))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            };
+            let mut body = spaced(&values);
+            if !fields.is_empty() {
+                body = body + ";" + &spaced(&fields);
+            }
+            body + " "
+        };
 
-        Ok(s)
+        Ok(match options.version {
+            FormatVersion::V1 => format!("[ed|{}|]", body),
+            FormatVersion::V2 => format!("[ed2|{}|{}]", body, Self::checksum(&body)),
+        })
+    }
+
+    // A short checksum of the body of v2 data, to catch data that's been
+    // truncated, or edited by hand. This is part of the format, so must never
+    // change: it's the top 16 bits of the (64 bit) FNV-1a hash of the body.
+    fn checksum(body: &str) -> String {
+        let hash = body.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        format!("{:04x}", hash >> 48)
     }
 
     #[flame]
@@ -75,7 +356,46 @@ impl EllingtonData {
     pub fn format_readable(self: &Self) -> Option<String> {
         let mut output = String::new();
         for (alg, tmpo) in &self.algs {
-            output += &format!("Algorithm: {}, Tempo: {}\n", alg, tmpo);
+            match self.estimates.get(alg) {
+                Some(TempoEstimate {
+                    bpm,
+                    confidence: Some(c),
+                    ..
+                }) => {
+                    output += &format!(
+                        "Algorithm: {}, Tempo: {} ({:.2}, confidence {:.2})\n",
+                        alg, tmpo, bpm, c
+                    )
+                }
+                Some(estimate) => {
+                    output += &format!(
+                        "Algorithm: {}, Tempo: {} ({:.2})\n",
+                        alg, tmpo, estimate.bpm
+                    )
+                }
+                None => output += &format!("Algorithm: {}, Tempo: {}\n", alg, tmpo),
+            }
+        }
+        if let Some(EnsembleSummary {
+            disagreement: true,
+            dissenting,
+            ..
+        }) = &self.ensemble
+        {
+            output += &format!("Estimators disagree! Dissenting: {:?}\n", dissenting);
+        }
+        if let Some(meter) = &self.meter {
+            output += &format!(
+                "Meter: {:?} ({} beats to the bar)\n",
+                meter,
+                meter.beats_per_bar()
+            );
+        }
+        if let Some(swing) = &self.swing {
+            output += &format!(
+                "Swing ratio: {:.2} (strength {:.2})\n",
+                swing.ratio, swing.strength
+            );
         }
         Some(output)
     }
@@ -87,42 +407,169 @@ impl EllingtonData {
 
     fn regex() -> &'static Regex {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"\[ed\|(.*)\|\]").unwrap();
+            static ref RE: Regex =
+                Regex::new(r"\[ed(?P<version>2?)\|(?P<body>.*?)\|(?P<checksum>[0-9a-f]{4})?\]")
+                    .unwrap();
         }
         &RE
     }
 
-    named!(parse_ed_fragment<&str, Vec<(&str, &str)>>,
-        terminated!(preceded!(tag!("[ed|"),
-        separated_list!(
-            tag!(","),
-            separated_pair!(
-                ws!(nom::alpha),
-                tag!("~"),
-                ws!(
-                    alt!(nom::digit| tag!("na"))
-                )
-            )
-        )), tag!("|]"))
+    // Algorithm names may be those of configured estimators, e.g. "madmom-dbn"
+    fn is_name_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '-' || c == '_'
+    }
+
+    // A single value, e.g. "naive~142.5", or "naive~142.5:c=0.83:v=1" in v2
+    named!(parse_value<CompleteStr, (CompleteStr, CompleteStr, Vec<CompleteStr>)>,
+        do_parse!(
+            name: ws!(take_while1!(Self::is_name_char)) >>
+            tag!("~") >>
+            bpm: ws!(
+                alt!(recognize!(pair!(nom::digit, opt!(pair!(tag!("."), nom::digit))))
+                    | tag!("na"))
+            ) >>
+            attributes: many0!(ws!(preceded!(tag!(":"), take_while1!(Self::is_attribute_char)))) >>
+            eof!() >>
+            (name, bpm, attributes)
+        )
     );
 
+    fn is_attribute_char(c: char) -> bool {
+        ValueAttributes::is_value_char(c) || c == '='
+    }
+
+    // The (comma separated) items of part of the body of some data, along with
+    // the offset of each, given the offset of the text.
+    fn items(text: &str, mut offset: usize) -> Vec<(usize, &str)> {
+        if text.trim().is_empty() {
+            return vec![];
+        }
+        text.split(',')
+            .map(|item| {
+                let start = offset + item.len() - item.trim_start().len();
+                offset += item.len() + 1;
+                (start, item)
+            })
+            .collect()
+    }
+
+    // Parse ellington data (of either version) from a comment. In strict mode,
+    // any error fails the parse, while in lenient mode, the values that can be
+    // read are kept, along with errors for those that can't.
     #[flame]
-    pub fn parse(comment: &String) -> Option<EllingtonData> {
-        let captures = Self::regex().captures(comment.as_str())?;
-
-        // get the first capture, and try to parse it
-        match Self::parse_ed_fragment(captures.get(0)?.as_str()) {
-            Ok((_, pairs)) => {
-                let mut map = BTreeMap::new();
-                for (algorithm, bpm) in pairs {
-                    // It would be good to think more deeply about what BpmE should do when it fails, as at the moment it always returns "NA", which might not be the best solution...
-                    map.insert(AlgorithmE::parse(algorithm), BpmE::parse(bpm));
-                }
+    pub fn parse_with(
+        comment: &str,
+        mode: ParseMode,
+    ) -> Result<(EllingtonData, Vec<ParseError>), ParseError> {
+        let captures = Self::regex()
+            .captures(comment)
+            .ok_or(ParseError::new(0, ParseReason::NoData))?;
+        let data = captures.get(0).unwrap();
+        let body = captures.name("body").unwrap();
 
-                Some(EllingtonData { algs: map })
+        let mut errors = vec![];
+        let mut fail = |error: ParseError| match mode {
+            ParseMode::Strict => Err(error),
+            ParseMode::Lenient => {
+                errors.push(error);
+                Ok(())
+            }
+        };
+
+        let version = captures.name("version").unwrap().as_str();
+        match (version, captures.name("checksum")) {
+            ("", None) => {}
+            ("2", Some(checksum)) => {
+                let expected = Self::checksum(body.as_str());
+                if checksum.as_str() != expected {
+                    fail(ParseError::new(
+                        checksum.start(),
+                        ParseReason::Checksum {
+                            expected,
+                            found: checksum.as_str().to_string(),
+                        },
+                    ))?;
+                }
             }
             _ => {
-                println!("Failed to parse ellington data from comment!");
+                // we can't tell which version this is, so can't recover anything from it
+                return Err(ParseError::new(
+                    data.start(),
+                    ParseReason::Version(data.as_str().to_string()),
+                ));
+            }
+        }
+
+        // only v2 data has fields, after the tempos
+        let (tempos, fields) = match body.as_str().find(';') {
+            Some(ix) if version == "2" => (
+                Self::items(&body.as_str()[..ix], body.start()),
+                Self::items(&body.as_str()[ix + 1..], body.start() + ix + 1),
+            ),
+            _ => (Self::items(body.as_str(), body.start()), vec![]),
+        };
+
+        let mut ed = EllingtonData::empty();
+        for (start, value) in tempos {
+            let syntax = || ParseError::new(start, ParseReason::Syntax(value.trim().to_string()));
+            let (name, bpm, attributes) = match Self::parse_value(CompleteStr(value)) {
+                // only v2 data has attributes
+                Ok((_, (_, _, ref attributes))) if version.is_empty() && !attributes.is_empty() => {
+                    fail(syntax())?;
+                    continue;
+                }
+                Ok((_, (name, bpm, attributes))) => (name.0, bpm.0, attributes),
+                Err(_) => {
+                    fail(syntax())?;
+                    continue;
+                }
+            };
+            let algorithm = AlgorithmE::parse(name);
+            let attributes: Vec<&str> = attributes.iter().map(|a| a.0).collect();
+            let attributes = ValueAttributes::parse(&attributes);
+            if attributes != ValueAttributes::default() {
+                ed.attributes.insert(algorithm.clone(), attributes);
+            }
+            // It would be good to think more deeply about what BpmE should do when it fails, as at the moment it always returns "NA", which might not be the best solution...
+            ed.algs.insert(algorithm, BpmE::parse(bpm));
+        }
+        for (start, field) in fields {
+            let field = field.trim();
+            let mut parts = field.splitn(2, '=');
+            let reason = match (parts.next(), parts.next()) {
+                (Some(name), Some(meter)) if name == Self::METER || name == &Self::METER[..1] => {
+                    match MeterE::parse(meter) {
+                        Some(meter) => {
+                            ed.meter = Some(meter);
+                            continue;
+                        }
+                        None => ParseReason::Meter(meter.to_string()),
+                    }
+                }
+                (Some(_), Some(_)) => {
+                    warn!("Ignoring unknown field {:?} in ellington data", field);
+                    continue;
+                }
+                _ => ParseReason::Syntax(field.to_string()),
+            };
+            fail(ParseError::new(start, reason))?;
+        }
+        Ok((ed, errors))
+    }
+
+    // Parse ellington data (of either version) from a comment, strictly
+    pub fn parse(comment: &String) -> Option<EllingtonData> {
+        match Self::parse_with(comment, ParseMode::Strict) {
+            Ok((ed, _)) => Some(ed),
+            Err(ParseError {
+                reason: ParseReason::NoData,
+                ..
+            }) => None,
+            Err(e) => {
+                warn!(
+                    "Failed to parse ellington data from comment {:?}: {}",
+                    comment, e
+                );
                 None
             }
         }
@@ -133,14 +580,14 @@ impl EllingtonData {
         self: &Self,
         comment: &String,
         append: UpdateBehaviour,
-        minimal: bool,
+        options: &FormatOptions,
     ) -> UpdateResult<String> {
-        let serialised = self.format(minimal)?;
+        let serialised = self.format_with(options)?;
 
         // test to see if there is any ellington data in the first place...
         let new_comment = match Self::regex()
             .captures(comment.as_str())
-            .and_then(|captures| captures.get(1))
+            .and_then(|captures| captures.name("body"))
         {
             Some(e) => {
                 info!("Found ellington data {:?} in comment.", e);
@@ -171,7 +618,7 @@ impl EllingtonData {
         // test to see if there is any json data in the first place...
         match Self::regex()
             .captures(comment.as_str())
-            .and_then(|captures| captures.get(1))
+            .and_then(|captures| captures.name("body"))
         {
             Some(e) => info!("Found ellington data {:?} in comment.", e),
             None => return Err(UpdateError::NoDataInComment),
@@ -183,12 +630,135 @@ impl EllingtonData {
     }
 }
 
+// How to choose between the tempos of two sets of ellington data (the left,
+// and the right) that both have a tempo for the same algorithm. Unless the
+// policy says otherwise, the left is preferred. Only tempos estimated by
+// ellington record when they were estimated, so PreferNewest treats tempos
+// read from comments and titles as older than any estimate in the library.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergePolicy {
+    PreferLeft,
+    PreferRight,
+    PreferNonNA,    // the left, unless it's NA and the right isn't
+    PreferVerified, // a tempo that a user has verified, otherwise as PreferNonNA
+    PreferNewest,   // the most recently estimated tempo, otherwise as PreferNonNA
+}
+
+impl MergePolicy {
+    pub fn parse(st: &str) -> Option<MergePolicy> {
+        match st {
+            "prefer-left" => Some(MergePolicy::PreferLeft),
+            "prefer-right" => Some(MergePolicy::PreferRight),
+            "prefer-non-na" => Some(MergePolicy::PreferNonNA),
+            "prefer-verified" => Some(MergePolicy::PreferVerified),
+            "prefer-newest" => Some(MergePolicy::PreferNewest),
+            _ => None,
+        }
+    }
+
+    // Whether to take the tempo of an algorithm from the left
+    fn prefers_left(
+        self,
+        left: &EllingtonData,
+        right: &EllingtonData,
+        algorithm: &Algorithm,
+    ) -> bool {
+        let non_na = || left.algs[algorithm] != BpmE::NA || right.algs[algorithm] == BpmE::NA;
+        match self {
+            MergePolicy::PreferLeft => true,
+            MergePolicy::PreferRight => false,
+            MergePolicy::PreferNonNA => non_na(),
+            MergePolicy::PreferVerified => {
+                match (
+                    left.value_attributes(algorithm).verified,
+                    right.value_attributes(algorithm).verified,
+                ) {
+                    (true, false) => true,
+                    (false, true) => false,
+                    _ => non_na(),
+                }
+            }
+            MergePolicy::PreferNewest => {
+                let estimated_at =
+                    |ed: &EllingtonData| ed.estimates.get(algorithm).and_then(|e| e.estimated_at);
+                match (estimated_at(left), estimated_at(right)) {
+                    (Some(l), Some(r)) if l != r => l > r,
+                    (Some(_), None) => true,
+                    (None, Some(_)) => false,
+                    _ => non_na(),
+                }
+            }
+        }
+    }
+}
+
+impl EllingtonData {
+    // Combine two sets of ellington data. Where both have a tempo for an
+    // algorithm, the policy decides which to take, and the estimate and
+    // attributes of the tempo are taken along with it.
+    pub fn merge(self, rhs: EllingtonData, policy: MergePolicy) -> EllingtonData {
+        let mut merged = self.clone();
+        for (algorithm, bpm) in &rhs.algs {
+            if self.algs.contains_key(algorithm) && policy.prefers_left(&self, &rhs, algorithm) {
+                continue;
+            }
+            merged.algs.insert(algorithm.clone(), bpm.clone());
+            match rhs.estimates.get(algorithm) {
+                Some(estimate) => merged.estimates.insert(algorithm.clone(), estimate.clone()),
+                None => merged.estimates.remove(algorithm),
+            };
+            match rhs.attributes.get(algorithm) {
+                Some(attributes) => merged
+                    .attributes
+                    .insert(algorithm.clone(), attributes.clone()),
+                None => merged.attributes.remove(algorithm),
+            };
+            if *algorithm == AlgorithmE::Ensemble {
+                merged.ensemble = rhs.ensemble.clone();
+            }
+        }
+        // everything else is taken from whichever side is preferred, if it has it
+        let (preferred, other) = match policy {
+            MergePolicy::PreferRight => (&rhs, &self),
+            _ => (&self, &rhs),
+        };
+        merged.swing = preferred.swing.clone().or_else(|| other.swing.clone());
+        merged.meter = preferred.meter.or(other.meter);
+        if !merged.algs.contains_key(&AlgorithmE::Ensemble) {
+            merged.ensemble = preferred
+                .ensemble
+                .clone()
+                .or_else(|| other.ensemble.clone());
+        }
+        merged
+    }
+
+    // Combine ellington data from several sources, given in order of
+    // preference. The policy chooses between the first source (the left) and
+    // the rest (the right), which are themselves combined in order, so that
+    // under PreferRight the second source wins over the third.
+    pub fn merge_all(sources: Vec<EllingtonData>, policy: MergePolicy) -> EllingtonData {
+        let rest_policy = match policy {
+            MergePolicy::PreferRight => MergePolicy::PreferLeft,
+            policy => policy,
+        };
+        let mut sources = sources.into_iter();
+        let first = sources.next().unwrap_or_else(EllingtonData::empty);
+        match sources.next() {
+            Some(second) => {
+                let rest = sources.fold(second, |acc, ed| acc.merge(ed, rest_policy));
+                first.merge(rest, policy)
+            }
+            None => first,
+        }
+    }
+}
+
+// Adding ellington data merges it, with the right hand side taking precedence
 impl ops::Add<EllingtonData> for EllingtonData {
     type Output = EllingtonData;
     fn add(self, rhs: EllingtonData) -> EllingtonData {
-        let mut algs: BTreeMap<Algorithm, Bpm> = self.algs.clone();
-        algs.append(&mut rhs.algs.clone());
-        EllingtonData { algs: algs }
+        self.merge(rhs, MergePolicy::PreferRight)
     }
 }
 
@@ -196,6 +766,8 @@ impl ops::Add<EllingtonData> for EllingtonData {
 mod tests {
     use super::UpdateError::*;
     use super::*;
+    use estimators::registry::{BuiltinEstimator, Registry};
+    use estimators::TagTempoEstimator;
 
     #[test]
     fn serialise() {
@@ -207,6 +779,422 @@ mod tests {
         }
     }
 
+    #[test]
+    fn estimates() {
+        let mut ed = EllingtonData::empty();
+        ed.insert_estimate(
+            AlgorithmE::Naive,
+            TempoEstimate::from_candidates(vec![
+                TempoCandidate {
+                    bpm: 61.5,
+                    strength: 1.0,
+                },
+                TempoCandidate {
+                    bpm: 123.0,
+                    strength: 3.0,
+                },
+            ])
+            .unwrap(),
+        );
+        assert_eq!(ed.format(false).unwrap(), "[ed| naive~123 |]");
+        assert_eq!(ed.estimates[&AlgorithmE::Naive].confidence, Some(0.75));
+        let json = ed.format_json().unwrap();
+        assert_eq!(EllingtonData::from_json(json), Some(ed));
+    }
+
+    #[test]
+    fn meter() {
+        let mut ed = EllingtonData::with_algorithm(AlgorithmE::Actual, BpmE::Bpm(150));
+        ed.meter = Some(MeterE::Triple);
+        let v2 = |minimal| FormatOptions {
+            minimal,
+            version: FormatVersion::V2,
+            ..FormatOptions::default()
+        };
+        let full = ed.format_with(&v2(false)).unwrap();
+        let short = ed.format_with(&v2(true)).unwrap();
+        assert!(full.starts_with("[ed2| actual~150; meter=3 |"));
+        assert!(short.starts_with("[ed2|a~150;m=3|"));
+        assert_eq!(EllingtonData::parse(&full), Some(ed.clone()));
+        assert_eq!(EllingtonData::parse(&short), Some(ed.clone()));
+
+        // the meter isn't written to v1 data, as older versions would read it as a tempo
+        assert_eq!(ed.format(false).unwrap(), "[ed| actual~150 |]");
+        // and so algorithms may be called "meter", or "m"
+        let comment = "[ed| m~121, meter~120 |]".to_string();
+        let parsed = EllingtonData::parse(&comment).unwrap();
+        assert_eq!(parsed.meter, None);
+        assert_eq!(
+            parsed.algs[&AlgorithmE::Custom("meter".to_string())],
+            BpmE::Bpm(120)
+        );
+        assert_eq!(parsed.format(false).unwrap(), comment);
+    }
+
+    #[test]
+    fn fractional() {
+        let mut ed = EllingtonData::with_algorithm(AlgorithmE::Naive, BpmE::parse("142.5"));
+        ed.algs.insert(AlgorithmE::Actual, BpmE::parse("150"));
+        assert_eq!(ed.algs[&AlgorithmE::Naive], BpmE::Fractional(1425, 1));
+        assert_eq!(ed.format(true).unwrap(), "[ed|a~150,n~142.5|]");
+        assert_eq!(ed.format(false).unwrap(), "[ed| actual~150, naive~142.5 |]");
+        let rounded = FormatOptions {
+            minimal: true,
+            precision: Some(0),
+            ..FormatOptions::default()
+        };
+        assert_eq!(ed.format_with(&rounded).unwrap(), "[ed|a~150,n~143|]");
+        assert_eq!(
+            EllingtonData::parse(&ed.format(true).unwrap()),
+            Some(ed.clone())
+        );
+        assert_eq!(
+            EllingtonData::parse(&ed.format(false).unwrap()),
+            Some(ed.clone())
+        );
+
+        // estimates are written to the precision asked for
+        ed.insert_estimate(AlgorithmE::Onset, TempoEstimate::from_bpm(97.125, None));
+        assert_eq!(ed.algs[&AlgorithmE::Onset], BpmE::Fractional(97125, 3));
+        assert_eq!(
+            ed.format_with(&FormatOptions {
+                precision: Some(2),
+                ..rounded
+            })
+            .unwrap(),
+            "[ed|a~150,n~142.5,o~97.13|]"
+        );
+
+        // and otherwise to the precision they were estimated to
+        let mut estimated = EllingtonData::empty();
+        estimated.insert_estimate(AlgorithmE::Naive, TempoEstimate::from_bpm(127.35, None));
+        let comment = estimated.format(true).unwrap();
+        assert_eq!(comment, "[ed|n~127.35|]");
+        let parsed = EllingtonData::parse(&comment).unwrap();
+        assert_eq!(parsed.algs, estimated.algs);
+        assert_eq!(parsed.algs[&AlgorithmE::Naive].value(), Some(127.35));
+
+        assert_eq!(BpmE::parse("120.000"), BpmE::Bpm(120));
+        assert_eq!(BpmE::from_f64(99.96, 1).to_string(), "100");
+        assert_eq!(BpmE::Fractional(1205, 2).to_string(), "12.05");
+        assert!(BpmE::Fractional(1425, 1) < BpmE::Bpm(143));
+        assert!(BpmE::Bpm(142) < BpmE::Fractional(1425, 1));
+        assert!(BpmE::Bpm(500) < BpmE::NA);
+    }
+
+    #[test]
+    fn unnormalised() {
+        let stored = |json: &str| serde_json::from_str::<BpmE>(json).unwrap();
+        assert_eq!(stored(r#"{"Fractional":[1200,1]}"#), BpmE::Bpm(120));
+        assert_eq!(stored(r#"{"Fractional":[1425,1]}"#), BpmE::parse("142.5"));
+        let precise = stored(r#"{"Fractional":[12345678,7]}"#);
+        assert_eq!(precise, BpmE::parse("1.234568"));
+        assert_eq!(stored(r#"{"Fractional":[120,40]}"#), BpmE::Bpm(0));
+        assert_eq!(stored(r#"{"Bpm":120}"#), BpmE::Bpm(120));
+        assert_eq!(stored(r#""NA""#), BpmE::NA);
+
+        // tempos with more places than we store still compare and display
+        assert_eq!(BpmE::Fractional(1205, 20).to_string(), "0");
+        assert_eq!(BpmE::Fractional(-12345678, 7).to_string(), "-1.234568");
+        assert!(BpmE::Fractional(10_000_001, 7) < BpmE::Fractional(1_000_001, 6));
+        assert!(BpmE::Fractional(10_000_006, 7) > BpmE::Fractional(1_000_000, 6));
+        assert!(BpmE::Fractional(12, 8) >= BpmE::Bpm(0));
+        assert!(BpmE::Fractional(12, 8) <= BpmE::Bpm(0));
+    }
+
+    #[test]
+    fn algorithms() {
+        assert_eq!(AlgorithmE::parse("bellson"), AlgorithmE::Bellson);
+        assert_eq!(AlgorithmE::parse("b"), AlgorithmE::Bellson);
+        assert_eq!(AlgorithmE::parse("Naive"), AlgorithmE::Naive);
+        assert_eq!(
+            AlgorithmE::parse("beatroot"),
+            AlgorithmE::Custom("beatroot".to_string())
+        );
+
+        // algorithms that we don't know survive being read and written again
+        let comment = "[ed| actual~120, beatroot~121, madmom-dbn~60.5 |]".to_string();
+        let ed = EllingtonData::parse(&comment).unwrap();
+        assert_eq!(
+            ed.algs[&AlgorithmE::Custom("madmom-dbn".to_string())],
+            BpmE::Fractional(605, 1)
+        );
+        assert_eq!(ed.format(false).unwrap(), comment);
+        assert_eq!(
+            ed.format(true).unwrap(),
+            "[ed|a~120,beatroot~121,madmom-dbn~60.5|]"
+        );
+        assert_eq!(
+            EllingtonData::parse(&ed.format(true).unwrap()),
+            Some(ed.clone())
+        );
+
+        // including in the library
+        let json = ed.format_json().unwrap();
+        assert_eq!(EllingtonData::from_json(json), Some(ed));
+
+        // custom algorithms are always written by their full name, even if the
+        // estimator that produces them has a short code
+        let mut registry = Registry::empty();
+        registry.register(BuiltinEstimator {
+            name: "kick",
+            short_code: "k",
+            algorithm: AlgorithmE::Custom("kick".to_string()),
+            constructor: |_| Box::new(TagTempoEstimator {}),
+            version: "1",
+            correctable: false,
+            reads: &[],
+        });
+        let kick = registry.lookup("k").unwrap().algorithm();
+        let ed = EllingtonData::with_algorithm(kick, BpmE::Bpm(98));
+        assert_eq!(ed.format(true).unwrap(), "[ed|kick~98|]");
+        assert_eq!(ed.format(false).unwrap(), "[ed| kick~98 |]");
+        assert_eq!(EllingtonData::parse(&"[ed|kick~98|]".to_string()), Some(ed));
+        let short = EllingtonData::parse(&"[ed|k~98|]".to_string()).unwrap();
+        let k = AlgorithmE::Custom("k".to_string());
+        assert!(short.algs.contains_key(&k));
+    }
+
+    #[test]
+    fn checksum() {
+        // checksums are written to comments, so must be the same across builds
+        assert_eq!(EllingtonData::checksum(""), "cbf2");
+        assert_eq!(EllingtonData::checksum("n~120"), "e01b");
+        assert_eq!(EllingtonData::checksum(" actual~120 "), "b87e");
+        assert_eq!(
+            EllingtonData::checksum("a~120,n~142.5:c=0.83:v=1;m=4"),
+            "5ed2"
+        );
+    }
+
+    #[test]
+    fn version_two() {
+        let mut ed = EllingtonData::with_algorithm(AlgorithmE::Actual, BpmE::Bpm(120));
+        ed.meter = Some(MeterE::Duple);
+        ed.attributes.insert(
+            AlgorithmE::Actual,
+            ValueAttributes {
+                verified: true,
+                ..ValueAttributes::default()
+            },
+        );
+        let mut estimate = TempoEstimate::from_bpm(142.5, Some(0.834));
+        estimate.provenance = Some(Provenance {
+            version: "1".to_string(),
+            params: "0".to_string(),
+        });
+        ed.insert_estimate(AlgorithmE::Naive, estimate);
+
+        let full = FormatOptions {
+            version: FormatVersion::V2,
+            precision: Some(1),
+            ..FormatOptions::default()
+        };
+        let minimal = FormatOptions {
+            minimal: true,
+            ..full.clone()
+        };
+        let body = " actual~120:verified, naive~142.5:confidence=0.83:version=1; meter=4 ";
+        assert_eq!(
+            ed.format_with(&full).unwrap(),
+            format!("[ed2|{}|{}]", body, EllingtonData::checksum(body))
+        );
+        let short = ed.format_with(&minimal).unwrap();
+        assert!(short.starts_with("[ed2|a~120:u,n~142.5:c=0.83:v=1;m=4|"));
+
+        // estimates aren't stored in comments, but their attributes are
+        let mut expected = EllingtonData::with_algorithm(AlgorithmE::Actual, BpmE::Bpm(120));
+        expected
+            .algs
+            .insert(AlgorithmE::Naive, BpmE::Fractional(1425, 1));
+        expected.meter = Some(MeterE::Duple);
+        expected.attributes = ed.attributes.clone();
+        expected.attributes.insert(
+            AlgorithmE::Naive,
+            ValueAttributes {
+                confidence: Some(0.83),
+                version: Some("1".to_string()),
+                verified: false,
+            },
+        );
+        assert_eq!(
+            EllingtonData::parse(&ed.format_with(&full).unwrap()),
+            Some(expected.clone())
+        );
+        assert_eq!(EllingtonData::parse(&short), Some(expected.clone()));
+        assert_eq!(
+            EllingtonData::parse(&format!("Some tags {} more tags", short)),
+            Some(expected.clone())
+        );
+
+        // damaged data is rejected
+        assert!(EllingtonData::parse(&short.replace("120", "121")).is_none());
+        assert!(EllingtonData::parse(&"[ed2| actual~120 |]".to_string()).is_none());
+
+        // and both versions can be updated, and cleared
+        let v1 = "chugging, [ed| naive~130 |] swinging".to_string();
+        let updated = expected
+            .update_data(&v1, UpdateBehaviour::FailIfNone, &minimal)
+            .unwrap();
+        assert_eq!(updated, format!("chugging, {} swinging", short));
+        assert_eq!(
+            EllingtonData::clear_data(&updated).unwrap(),
+            "chugging,  swinging"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let comment = "Swing! [ed| actual~120, naive~fast, onset~121 |]".to_string();
+        assert_eq!(EllingtonData::parse(&comment), None);
+        assert_eq!(
+            EllingtonData::parse_with(&comment, ParseMode::Strict),
+            Err(ParseError::new(
+                24,
+                ParseReason::Syntax("naive~fast".to_string())
+            ))
+        );
+
+        // leniently, we keep the values that we can read
+        let (ed, errors) = EllingtonData::parse_with(&comment, ParseMode::Lenient).unwrap();
+        assert_eq!(ed.algs.len(), 2);
+        assert_eq!(ed.algs[&AlgorithmE::Onset], BpmE::Bpm(121));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(&comment[errors[0].offset..errors[0].offset + 5], "naive");
+
+        let errors = |comment: &str| match EllingtonData::parse_with(comment, ParseMode::Lenient) {
+            Ok((_, errors)) => errors.into_iter().map(|e| e.reason).collect(),
+            Err(e) => vec![e.reason],
+        };
+        assert_eq!(errors("no data here"), vec![ParseReason::NoData]);
+        assert_eq!(
+            errors("[ed2|;m=5|9400]"),
+            vec![ParseReason::Meter("5".to_string())]
+        );
+        assert_eq!(
+            errors("[ed| naive~120:u |]"),
+            vec![ParseReason::Syntax("naive~120:u".to_string())]
+        );
+        assert_eq!(
+            errors("[ed| naive~120 |abcd]"),
+            vec![ParseReason::Version("[ed| naive~120 |abcd]".to_string())]
+        );
+        assert_eq!(
+            errors("[ed2|n~120|0000]"),
+            vec![ParseReason::Checksum {
+                expected: "e01b".to_string(),
+                found: "0000".to_string()
+            }]
+        );
+        assert!(errors("[ed||]").is_empty());
+    }
+
+    #[test]
+    fn merge() {
+        let mut left = EllingtonData::with_algorithm(AlgorithmE::Actual, BpmE::NA);
+        left.algs.insert(AlgorithmE::Naive, BpmE::Bpm(120));
+        left.meter = Some(MeterE::Duple);
+        let mut right = EllingtonData::with_algorithm(AlgorithmE::Actual, BpmE::Bpm(90));
+        right.algs.insert(AlgorithmE::Onset, BpmE::Bpm(91));
+        right.meter = Some(MeterE::Triple);
+        let mut estimate = TempoEstimate::from_bpm(60.2, None);
+        estimate.estimated_at = Some(1000);
+        right.insert_estimate(AlgorithmE::Naive, estimate);
+
+        let merged = |left: &EllingtonData, policy| left.clone().merge(right.clone(), policy);
+        let tempos = |ed: EllingtonData| {
+            (
+                ed.algs[&AlgorithmE::Actual].clone(),
+                ed.algs[&AlgorithmE::Naive].clone(),
+                ed.algs[&AlgorithmE::Onset].clone(),
+            )
+        };
+        assert_eq!(
+            tempos(merged(&left, MergePolicy::PreferLeft)),
+            (BpmE::NA, BpmE::Bpm(120), BpmE::Bpm(91))
+        );
+        assert_eq!(
+            tempos(merged(&left, MergePolicy::PreferRight)),
+            (BpmE::Bpm(90), BpmE::Fractional(602, 1), BpmE::Bpm(91))
+        );
+        assert_eq!(
+            tempos(merged(&left, MergePolicy::PreferNonNA)),
+            (BpmE::Bpm(90), BpmE::Bpm(120), BpmE::Bpm(91))
+        );
+        assert_eq!(
+            tempos(merged(&left, MergePolicy::PreferNewest)),
+            (BpmE::Bpm(90), BpmE::Fractional(602, 1), BpmE::Bpm(91))
+        );
+        assert_eq!(
+            merged(&left, MergePolicy::PreferLeft).meter,
+            Some(MeterE::Duple)
+        );
+        assert_eq!(
+            merged(&left, MergePolicy::PreferRight).meter,
+            Some(MeterE::Triple)
+        );
+        assert_eq!(
+            left.clone() + right.clone(),
+            merged(&left, MergePolicy::PreferRight)
+        );
+
+        // estimates go with their tempos
+        assert!(merged(&left, MergePolicy::PreferNonNA).estimates.is_empty());
+        assert_eq!(
+            merged(&left, MergePolicy::PreferNewest).estimates[&AlgorithmE::Naive].bpm,
+            60.2
+        );
+
+        left.attributes.insert(
+            AlgorithmE::Actual,
+            ValueAttributes {
+                verified: true,
+                ..ValueAttributes::default()
+            },
+        );
+        let verified = merged(&left, MergePolicy::PreferVerified);
+        assert_eq!(verified.algs[&AlgorithmE::Actual], BpmE::NA);
+        assert!(verified.value_attributes(&AlgorithmE::Actual).verified);
+        assert_eq!(verified.algs[&AlgorithmE::Naive], BpmE::Bpm(120));
+    }
+
+    #[test]
+    fn merge_all() {
+        let source = |actual: BpmE, meter: MeterE| {
+            let mut ed = EllingtonData::with_algorithm(AlgorithmE::Actual, actual);
+            ed.meter = Some(meter);
+            ed
+        };
+        let sources = || {
+            vec![
+                source(BpmE::NA, MeterE::Duple),
+                source(BpmE::Bpm(90), MeterE::Triple),
+                source(BpmE::Bpm(100), MeterE::Duple),
+            ]
+        };
+        let merged = |policy| EllingtonData::merge_all(sources(), policy);
+        let actual = |ed: EllingtonData| (ed.algs[&AlgorithmE::Actual].clone(), ed.meter);
+
+        assert_eq!(
+            actual(merged(MergePolicy::PreferLeft)),
+            (BpmE::NA, Some(MeterE::Duple))
+        );
+        // the rest are still taken in order of preference
+        assert_eq!(
+            actual(merged(MergePolicy::PreferRight)),
+            (BpmE::Bpm(90), Some(MeterE::Triple))
+        );
+        assert_eq!(
+            actual(merged(MergePolicy::PreferNonNA)),
+            (BpmE::Bpm(90), Some(MeterE::Duple))
+        );
+
+        let only = source(BpmE::Bpm(90), MeterE::Triple);
+        let all = |sources| EllingtonData::merge_all(sources, MergePolicy::PreferRight);
+        assert_eq!(all(vec![only.clone()]), only);
+        assert_eq!(all(vec![]), EllingtonData::empty());
+    }
+
     mod deserialise {
         use super::*;
         mod good {
@@ -1,9 +1,18 @@
 pub mod ellingtondata;
 pub mod filemetadata;
 pub mod statistics;
+pub mod tags;
 pub mod trackmetadata;
 
-use estimators::TempoEstimator;
+use analysis::beats::*;
+use analysis::curve::TempoCurve;
+use analysis::meter::MeterAnalyser;
+use analysis::properties::*;
+use analysis::swing::SwingAnalyser;
+use estimators::context::AnalysisContext;
+use estimators::ensemble::*;
+use estimators::registry;
+use estimators::EstimatorParams;
 use library::ellingtondata::*;
 use library::filemetadata::FileMetadata;
 use library::trackmetadata::*;
@@ -30,6 +39,12 @@ pub struct Entry {
     pub metadata: Option<TrackMetadata>, // metadata stored int he track itself
     pub eldata: EllingtonData,  // Ellington data
     pub vsmarker: bool,         // Marker for ML purposes, is this track in the validation set (vs)?
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub beats: Option<Beats>, // the times of the beats in the track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<TempoCurve>, // how the tempo changes over the track
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<AudioProperties>, // duration, format and loudness of the track
 }
 
 impl Entry {
@@ -40,7 +55,18 @@ impl Entry {
         // TODO: Implement different readers here!
         let metadata = TrackMetadata::from_file(&path);
         let eldata = match &metadata {
-            Some(m) => m.comment_metadata() + m.title_metadata(),
+            Some(m) => {
+                let (comments, comment_errors) = m.comment_metadata(ParseMode::Lenient);
+                let (title, title_errors) = m.title_metadata(ParseMode::Lenient);
+                for e in comment_errors {
+                    warn!("Malformed ellington data in comments of {:?}, {}", path, e);
+                }
+                for e in title_errors {
+                    warn!("Malformed ellington data in title of {:?}, {}", path, e);
+                }
+                // data in the title takes precedence, unless it's "na"
+                title.merge(comments, MergePolicy::PreferNonNA)
+            }
             None => EllingtonData::empty(),
         };
         Entry {
@@ -49,6 +75,9 @@ impl Entry {
             metadata: metadata,
             eldata: eldata,
             vsmarker: false,
+            beats: None,
+            curve: None,
+            properties: None,
         }
     }
 }
@@ -270,46 +299,177 @@ impl Library {
     }
 
     /*
-       Run an analysis pipeline over each audio track in the library
+       Run an analysis pipeline over each audio track in the library, using
+       the named estimator from the estimator registry, configured with the
+       given parameters
     */
     #[flame]
-    pub fn run_pipeline<P: TempoEstimator>(self: &mut Self) -> () {
+    pub fn run_pipeline(self: &mut Self, name: &str, params: &EstimatorParams) -> Option<()> {
         info!("Running tempo estimator over ellington library.");
-        info!("Using estimator: {:?}", P::ALGORITHM);
+        if !params.valid() {
+            error!(
+                "Cannot search for tempos between {} and {} bpm",
+                params.min_bpm, params.max_bpm
+            );
+            return None;
+        }
+        let factory = registry::lookup(name).or_else(|| {
+            error!(
+                "Could not find estimator {:?}, registered estimators: {:?}",
+                name,
+                registry::registry().names()
+            );
+            None
+        })?;
+        let algorithm = factory.algorithm();
+        info!(
+            "Using estimator: {:?}, with parameters {:?}",
+            algorithm, params
+        );
         // iterate over our tracks, and run the pipeline
         let mut ix = 0;
         let lx = self.tracks.len();
         for entry in &mut self.tracks {
             info!(
                 "Running pipeline {:?} on track {:?}/{:?}:\n\t {:?}",
-                P::ALGORITHM,
-                ix,
-                lx,
-                entry.location
+                algorithm, ix, lx, entry.location
             );
             ix += 1;
+            // let the estimator know the meter of the track, if we know it. This is
+            // cheap, as factories share any expensive state (e.g. workers) between builds.
+            let track_params = EstimatorParams {
+                meter: entry.eldata.meter.or(params.meter),
+                ..params.clone()
+            };
             // get the pipeline result.
-            match P::run(&entry.location) {
-                Some(calculated_bpm) => {
+            let context = AnalysisContext::new(&entry.location, &track_params);
+            match factory.estimate(&track_params, &context) {
+                Some(estimate) => {
                     match &entry.metadata {
                         Some(m) => match m.bpm {
-                            Some(b) => {
-                                info!("Caculated bpm: {:?}, expected: {:?}", calculated_bpm, b)
-                            }
-                            _ => info!("Caculated bpm: {:?}", calculated_bpm),
+                            Some(b) => info!("Caculated bpm: {:?}, expected: {:?}", estimate, b),
+                            _ => info!("Caculated bpm: {:?}", estimate),
                         },
-                        _ => info!("Caculated bpm: {:?}", calculated_bpm),
+                        _ => info!("Caculated bpm: {:?}", estimate),
                     }
-                    entry
-                        .eldata
-                        .algs
-                        .insert(P::ALGORITHM, BpmE::Bpm(calculated_bpm));
+                    entry.eldata.insert_estimate(algorithm.clone(), estimate);
                 }
                 None => {
                     error!("Failed to calculate bpm for entry: {:?}", entry);
                 }
             }
         }
+        Some(())
+    }
+
+    /*
+       Combine the tempos of each track in the library into an ensemble tempo
+    */
+    #[flame]
+    pub fn run_ensemble(self: &mut Self, params: &EnsembleParams) {
+        let ensemble = EnsembleEstimator::new(params);
+        for entry in &mut self.tracks {
+            if entry.eldata.insert_ensemble(&ensemble).is_none() {
+                info!("No tempos to combine for entry: {:?}", entry.location);
+            }
+        }
+    }
+
+    /*
+       Track the beats of each audio track in the library, at the known
+       tempo of the track if there is one, and optionally detect its meter
+       and estimate its swing ratio. Each track is decoded once, and shared
+       between the analysers. Tracks that already have a result for an
+       analysis are skipped, unless force is given.
+    */
+    #[flame]
+    pub fn run_beat_analysis(
+        &mut self,
+        params: &EstimatorParams,
+        force: bool,
+        meter: bool,
+        swing: bool,
+    ) {
+        let tracker = BeatTracker::default();
+        let meter_analyser = MeterAnalyser::default();
+        let swing_analyser = SwingAnalyser::default();
+        let lx = self.tracks.len();
+        for (ix, entry) in self.tracks.iter_mut().enumerate() {
+            let find_meter = meter && (entry.eldata.meter.is_none() || force);
+            let find_beats = entry.beats.is_none() || force;
+            let find_swing = swing && (entry.eldata.swing.is_none() || force);
+            if !(find_meter || find_beats || find_swing) {
+                info!("Beats already analysed for entry: {:?}", entry.location);
+                continue;
+            }
+            info!(
+                "Analysing beats on track {:?}/{:?}:\n\t {:?}",
+                ix, lx, entry.location
+            );
+            let context = AnalysisContext::new(&entry.location, params);
+            let bpm = entry.eldata.known_bpm();
+
+            if find_meter {
+                match meter_analyser.run(&context, params, bpm) {
+                    Some(estimate) => {
+                        info!("Detected meter: {:?}", estimate);
+                        entry.eldata.meter = Some(estimate.meter);
+                    }
+                    None => error!("Failed to detect meter for entry: {:?}", entry.location),
+                }
+            }
+            if find_beats {
+                match tracker.run(&context, params, bpm) {
+                    Some(beats) => {
+                        info!("Found {} beats at {} bpm", beats.times.len(), beats.bpm);
+                        entry.beats = Some(beats);
+                    }
+                    None => error!("Failed to track beats for entry: {:?}", entry.location),
+                }
+            }
+            if find_swing {
+                match swing_analyser.run(&context, params, bpm) {
+                    Some(swing) => {
+                        info!("Estimated swing ratio: {:?}", swing);
+                        entry.eldata.swing = Some(swing);
+                    }
+                    None => error!("Failed to estimate swing for entry: {:?}", entry.location),
+                }
+            }
+        }
+    }
+
+    /*
+       Find the audio properties (duration, format, loudness) of each audio
+       track in the library. Tracks that already have properties are
+       skipped, unless force is given.
+    */
+    #[flame]
+    pub fn run_property_analysis(&mut self, force: bool) {
+        let analyser = PropertiesAnalyser {};
+        // loudness is measured over the whole of each track
+        let params = EstimatorParams {
+            analysis_length: None,
+            ..EstimatorParams::default()
+        };
+        let lx = self.tracks.len();
+        for (ix, entry) in self.tracks.iter_mut().enumerate() {
+            if entry.properties.is_some() && !force {
+                info!("Properties already found for entry: {:?}", entry.location);
+                continue;
+            }
+            info!(
+                "Finding properties of track {:?}/{:?}:\n\t {:?}",
+                ix, lx, entry.location
+            );
+            match analyser.run(&AnalysisContext::new(&entry.location, &params)) {
+                Some(properties) => {
+                    info!("Found properties: {:?}", properties);
+                    entry.properties = Some(properties);
+                }
+                None => error!("Failed to find properties for entry: {:?}", entry.location),
+            }
+        }
     }
 
     /*
@@ -325,23 +485,33 @@ impl Library {
         None
     }
 
+    /*
+        Get the entry for a path, adding a new one if there isn't one already.
+    */
+    fn entry_mut(&mut self, path: &PathBuf) -> &mut Entry {
+        let ix = match self.tracks.iter().position(|entry| entry.location == *path) {
+            Some(ix) => ix,
+            None => {
+                self.tracks.push(Entry::from_file(path.clone()));
+                self.tracks.len() - 1
+            }
+        };
+        &mut self.tracks[ix]
+    }
+
     /*
         Set an entry to a new one.
     */
     #[flame]
-    pub fn update(self: &mut Self, path: &PathBuf, eldata: EllingtonData) -> () {
-        let mut updated = false;
-        for entry in &mut self.tracks {
-            if entry.location == *path {
-                entry.eldata = eldata.clone();
-                updated = true;
-                break;
-            }
-        }
-        if !updated {
-            let mut et = Entry::from_file(PathBuf::from(path));
-            et.eldata = eldata;
-            self.tracks.push(et);
-        }
+    pub fn update(self: &mut Self, path: &PathBuf, eldata: EllingtonData) {
+        self.entry_mut(path).eldata = eldata;
+    }
+
+    /*
+        Set the tempo curve of an entry.
+    */
+    #[flame]
+    pub fn update_curve(&mut self, path: &PathBuf, curve: TempoCurve) {
+        self.entry_mut(path).curve = Some(curve);
     }
 }
@@ -0,0 +1,573 @@
+/*
+    A (minimal) reader for the tempos stored in the tags of audio files, as
+    taglib (through talamel) only gives us a single, integer, tempo. Tempos
+    live in different places in different formats, and are written to
+    different places by different (DJ) software:
+
+    - ID3v2 (mp3, aiff): TBPM, and TXXX frames described as BPM or TEMPO
+    - MP4 (m4a, alac): the tmpo atom
+    - Vorbis comments (flac, ogg, opus): BPM and TEMPO
+    - Serato: the "Serato Autotags" GEOB frame in ID3v2, or the (base64
+      encoded) SERATO_AUTOTAGS vorbis comment
+
+    Traktor and Rekordbox analysis is not read here (see "Tempos from DJ
+    software" in NOTES.md). Both also write the standard tempo fields above.
+*/
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TagSource {
+    Id3Tbpm,
+    Mp4Tmpo,
+    VorbisBpm,
+    Taglib,          // the tempo read by taglib, which may be any of the above
+    Id3Txxx(String), // a user defined ID3 frame, with its description
+    VorbisTempo,
+    Serato,
+}
+
+impl TagSource {
+    // How much we trust a tempo from the source, higher is better. The standard
+    // fields are usually set by hand (or by a tagger that the user chose),
+    // while DJ software writes its own analysis, which may be octave errors.
+    pub fn trust(&self) -> u32 {
+        match self {
+            TagSource::Id3Tbpm | TagSource::Mp4Tmpo | TagSource::VorbisBpm => 3,
+            TagSource::Taglib => 2,
+            TagSource::Id3Txxx(_) | TagSource::VorbisTempo => 1,
+            TagSource::Serato => 0,
+        }
+    }
+}
+
+impl fmt::Display for TagSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagSource::Id3Tbpm => write!(f, "ID3 TBPM"),
+            TagSource::Mp4Tmpo => write!(f, "MP4 tmpo"),
+            TagSource::VorbisBpm => write!(f, "Vorbis BPM"),
+            TagSource::Taglib => write!(f, "taglib"),
+            TagSource::Id3Txxx(description) => write!(f, "ID3 TXXX:{}", description),
+            TagSource::VorbisTempo => write!(f, "Vorbis TEMPO"),
+            TagSource::Serato => write!(f, "Serato"),
+        }
+    }
+}
+
+// A tempo found in the tags of a track, and where it was found.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagTempo {
+    pub source: TagSource,
+    pub bpm: f64,
+}
+
+impl TagTempo {
+    fn parse(source: TagSource, text: &str) -> Option<TagTempo> {
+        let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+        let bpm = text.replace(',', ".").parse::<f64>().ok()?;
+        if bpm > 0.0 && bpm < 1000.0 {
+            Some(TagTempo { source, bpm })
+        } else {
+            info!("Ignoring implausible tempo {:?} from {}", text, source);
+            None
+        }
+    }
+}
+
+// Read (at most) length bytes, where the length comes from the file, so
+// can't be trusted enough to allocate all of it before reading.
+fn read_up_to<R: Read>(reader: &mut R, length: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.take(length as u64).read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/*
+    ID3v2
+*/
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f))
+}
+
+// Split off text terminated by a (possibly two byte) null, for an ID3 text encoding
+fn split_terminated(encoding: u8, bytes: &[u8]) -> (&[u8], &[u8]) {
+    let end = match encoding {
+        1 | 2 => (0..bytes.len() / 2)
+            .map(|i| i * 2)
+            .find(|i| bytes[*i] == 0 && bytes[*i + 1] == 0)
+            .map(|i| (i, i + 2)),
+        _ => bytes.iter().position(|b| *b == 0).map(|i| (i, i + 1)),
+    };
+    match end {
+        Some((end, next)) => (&bytes[..end], &bytes[next..]),
+        None => (bytes, &[]),
+    }
+}
+
+fn decode_text(encoding: u8, bytes: &[u8]) -> String {
+    let utf16 = |big_endian: bool, bytes: &[u8]| {
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| {
+                if big_endian {
+                    BigEndian::read_u16(c)
+                } else {
+                    LittleEndian::read_u16(c)
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    match encoding {
+        0 => bytes.iter().map(|b| *b as char).collect(),
+        // with a byte order mark
+        1 if bytes.starts_with(&[0xff, 0xfe]) => utf16(false, &bytes[2..]),
+        1 if bytes.starts_with(&[0xfe, 0xff]) => utf16(true, &bytes[2..]),
+        1 | 2 => utf16(true, bytes),
+        _ => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
+// The data of a "Serato Autotags" object: a version, then null terminated
+// strings, of which the first is the tempo.
+fn serato_autotags(data: &[u8]) -> Option<TagTempo> {
+    let (bpm, _) = split_terminated(0, data.get(2..)?);
+    TagTempo::parse(TagSource::Serato, &String::from_utf8_lossy(bpm))
+}
+
+fn id3_frame(id: &str, body: &[u8]) -> Option<TagTempo> {
+    let (encoding, text) = body.split_first()?;
+    match id {
+        "TBPM" | "TBP" => {
+            // there may be several (null separated) values, take the first
+            let (value, _) = split_terminated(*encoding, text);
+            TagTempo::parse(TagSource::Id3Tbpm, &decode_text(*encoding, value))
+        }
+        "TXXX" | "TXX" => {
+            let (description, value) = split_terminated(*encoding, text);
+            let description = decode_text(*encoding, description);
+            match description.to_uppercase().as_str() {
+                "BPM" | "TEMPO" => TagTempo::parse(
+                    TagSource::Id3Txxx(description),
+                    &decode_text(*encoding, value),
+                ),
+                _ => None,
+            }
+        }
+        "GEOB" | "GEO" => {
+            let (_mime, rest) = split_terminated(0, text);
+            let (_filename, rest) = split_terminated(*encoding, rest);
+            let (description, data) = split_terminated(*encoding, rest);
+            match decode_text(*encoding, description).as_str() {
+                "Serato Autotags" => serato_autotags(data),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Read the tempos from the body of an ID3v2 tag, given its header
+fn id3_tempos(header: &[u8], body: &[u8]) -> Vec<TagTempo> {
+    let version = header[3];
+    let flags = header[5];
+    let mut body = body.to_vec();
+    if flags & 0x80 != 0 {
+        // undo unsynchronisation, i.e. remove the zeros inserted after each 0xff
+        let mut previous = 0;
+        body.retain(|byte| {
+            let keep = !(previous == 0xff && *byte == 0);
+            previous = *byte;
+            keep
+        });
+    }
+    let mut position = match (flags & 0x40 != 0, version) {
+        (true, 3) if body.len() >= 4 => 4 + BigEndian::read_u32(&body[..4]) as usize,
+        (true, 4) if body.len() >= 4 => syncsafe(&body[..4]),
+        _ => 0,
+    };
+
+    let (id_length, header_length) = match version {
+        2 => (3, 6),
+        _ => (4, 10),
+    };
+    let mut tempos = vec![];
+    while position + header_length <= body.len() && body[position] != 0 {
+        let frame = &body[position..position + header_length];
+        let id = String::from_utf8_lossy(&frame[..id_length]).to_string();
+        let size = match version {
+            2 => BigEndian::read_uint(&frame[3..6], 3) as usize,
+            3 => BigEndian::read_u32(&frame[4..8]) as usize,
+            _ => syncsafe(&frame[4..8]),
+        };
+        let start = position + header_length;
+        let end = (start + size).min(body.len());
+        if let Some(tempo) = id3_frame(&id, &body[start..end]) {
+            tempos.push(tempo);
+        }
+        position = end;
+    }
+    tempos
+}
+
+/*
+    MP4
+*/
+
+// Find the (first) child atom of the given type, within the body of an atom
+fn mp4_child<'a>(body: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    let mut position = 0;
+    while position + 8 <= body.len() {
+        let size = BigEndian::read_u32(&body[position..position + 4]) as usize;
+        let end = match size {
+            0 => body.len(),
+            s if s < 8 => return None,
+            s => (position + s).min(body.len()),
+        };
+        if &body[position + 4..position + 8] == kind {
+            return Some(&body[position + 8..end]);
+        }
+        position = end;
+    }
+    None
+}
+
+fn mp4_tempo(moov: &[u8]) -> Option<TagTempo> {
+    let meta = mp4_child(mp4_child(moov, b"udta")?, b"meta")?;
+    // meta is a "full" atom (with a version and flags) in mp4, but not in quicktime
+    let meta = match meta.get(..4) {
+        Some([0, 0, 0, 0]) => &meta[4..],
+        _ => meta,
+    };
+    let data = mp4_child(mp4_child(mp4_child(meta, b"ilst")?, b"tmpo")?, b"data")?;
+    // the value follows a type, and a locale
+    let value = data.get(8..10)?;
+    TagTempo::parse(TagSource::Mp4Tmpo, &BigEndian::read_u16(value).to_string())
+}
+
+// Read the moov atom from the top level of an mp4 file. Atom sizes come from
+// the file, so we check them against its length before trusting them.
+fn mp4_moov<R: Read + Seek>(reader: &mut R, start: u64) -> Option<Vec<u8>> {
+    let length = reader.seek(SeekFrom::End(0)).ok()?;
+    let mut position = start;
+    loop {
+        reader.seek(SeekFrom::Start(position)).ok()?;
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header[..8]).ok()?;
+        let (size, header_length) = match BigEndian::read_u32(&header[..4]) {
+            0 => return None,
+            1 => {
+                reader.read_exact(&mut header[8..]).ok()?;
+                (BigEndian::read_u64(&header[8..]), 16)
+            }
+            s => (u64::from(s), 8),
+        };
+        let end = position.checked_add(size)?;
+        if size < header_length || end > length {
+            return None;
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0u8; (size - header_length) as usize];
+            reader.read_exact(&mut moov).ok()?;
+            return Some(moov);
+        }
+        position = end;
+    }
+}
+
+/*
+    Vorbis comments
+*/
+
+fn base64(text: &str) -> Vec<u8> {
+    let values: Vec<u32> = text
+        .bytes()
+        .filter_map(|c| match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        })
+        .collect();
+    let mut bytes = vec![];
+    for chunk in values.chunks(4) {
+        let bits = chunk.iter().fold(0, |bits, v| (bits << 6) | v) << (6 * (4 - chunk.len()));
+        let count = (chunk.len() * 6) / 8;
+        bytes.extend((0..count).map(|i| (bits >> (16 - 8 * i)) as u8));
+    }
+    bytes
+}
+
+// Serato stores the same object as in its GEOB frame, prefixed with the GEOB header
+fn serato_vorbis(value: &str) -> Option<TagTempo> {
+    let data = base64(value);
+    let (_mime, rest) = split_terminated(0, &data);
+    let (_filename, rest) = split_terminated(0, rest);
+    let (description, data) = split_terminated(0, rest);
+    match description {
+        b"Serato Autotags" => serato_autotags(data),
+        _ => None,
+    }
+}
+
+fn vorbis_tempos(comments: &[u8]) -> Vec<TagTempo> {
+    let mut tempos = vec![];
+    let read_u32 = |position: usize| {
+        comments
+            .get(position..position + 4)
+            .map(|b| LittleEndian::read_u32(b) as usize)
+    };
+    let mut position = match read_u32(0) {
+        Some(vendor) => 4 + vendor,
+        None => return tempos,
+    };
+    let count = read_u32(position).unwrap_or(0);
+    position += 4;
+    for _ in 0..count {
+        let length = match read_u32(position) {
+            Some(l) => l,
+            None => break,
+        };
+        let start = (position + 4).min(comments.len());
+        let end = (start + length).min(comments.len());
+        let comment = String::from_utf8_lossy(&comments[start..end]);
+        let mut parts = comment.splitn(2, '=');
+        let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let tempo = match key.to_uppercase().as_str() {
+            "BPM" => TagTempo::parse(TagSource::VorbisBpm, value),
+            "TEMPO" => TagTempo::parse(TagSource::VorbisTempo, value),
+            "SERATO_AUTOTAGS" => serato_vorbis(value),
+            _ => None,
+        };
+        tempos.extend(tempo);
+        position = end;
+    }
+    tempos
+}
+
+fn flac_tempos<R: Read + Seek>(reader: &mut R) -> Vec<TagTempo> {
+    let mut header = [0u8; 4];
+    while reader.read_exact(&mut header).is_ok() {
+        let length = BigEndian::read_uint(&header[1..], 3) as usize;
+        // a block of type 4 holds the vorbis comments
+        if header[0] & 0x7f == 4 {
+            return read_up_to(reader, length)
+                .map(|comments| vorbis_tempos(&comments))
+                .unwrap_or_default();
+        }
+        if header[0] & 0x80 != 0 || reader.seek(SeekFrom::Current(length as i64)).is_err() {
+            break;
+        }
+    }
+    vec![]
+}
+
+fn ogg_tempos<R: Read>(reader: &mut R) -> Vec<TagTempo> {
+    // The comments are in the second packet, which is (almost always) near
+    // the start of the stream, so we don't bother to reassemble pages.
+    let mut head = vec![];
+    if reader.take(1 << 16).read_to_end(&mut head).is_err() {
+        return vec![];
+    }
+    for marker in [&b"\x03vorbis"[..], &b"OpusTags"[..]].iter() {
+        if let Some(i) = head.windows(marker.len()).position(|w| w == *marker) {
+            return vorbis_tempos(&head[i + marker.len()..]);
+        }
+    }
+    vec![]
+}
+
+// Read all of the tempos from the tags of an audio file
+pub fn read_tempos_from<R: Read + Seek>(reader: &mut R) -> Vec<TagTempo> {
+    let mut tempos = vec![];
+    let mut header = [0u8; 10];
+    if reader.read_exact(&mut header).is_err() {
+        return tempos;
+    }
+
+    // ID3v2 tags (normally) come before everything else, including flac streams
+    let mut start = 0;
+    if &header[..3] == b"ID3" {
+        let size = syncsafe(&header[6..10]);
+        if let Some(body) = read_up_to(reader, size) {
+            tempos.extend(id3_tempos(&header, &body));
+        }
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        start = (10 + size + footer) as u64;
+    }
+
+    let mut magic = [0u8; 8];
+    if reader.seek(SeekFrom::Start(start)).is_err() || reader.read_exact(&mut magic).is_err() {
+        return tempos;
+    }
+    if &magic[..4] == b"fLaC" {
+        if reader.seek(SeekFrom::Start(start + 4)).is_ok() {
+            tempos.extend(flac_tempos(reader));
+        }
+    } else if &magic[..4] == b"OggS" {
+        if reader.seek(SeekFrom::Start(start)).is_ok() {
+            tempos.extend(ogg_tempos(reader));
+        }
+    } else if &magic[4..] == b"ftyp" {
+        if let Some(moov) = mp4_moov(reader, start) {
+            tempos.extend(mp4_tempo(&moov));
+        }
+    }
+    tempos
+}
+
+pub fn read_tempos(path: &Path) -> Vec<TagTempo> {
+    match File::open(path) {
+        Ok(mut file) => read_tempos_from(&mut file),
+        Err(e) => {
+            error!("Could not read tags from {:?}, got io error {:?}", path, e);
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn id3_frame(id: &str, body: &[u8]) -> Vec<u8> {
+        let mut frame = id.as_bytes().to_vec();
+        frame.extend(&(body.len() as u32).to_be_bytes());
+        frame.extend(&[0, 0]);
+        frame.extend(body);
+        frame
+    }
+
+    fn id3_tag(frames: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = frames.concat();
+        let size = body.len();
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        tag.extend(body);
+        tag
+    }
+
+    fn atom(kind: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend(kind);
+        atom.extend(body);
+        atom
+    }
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut block = 6u32.to_le_bytes().to_vec();
+        block.extend(b"vendor");
+        block.extend(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend(&(comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        block
+    }
+
+    fn tempos(file: Vec<u8>) -> Vec<(TagSource, f64)> {
+        read_tempos_from(&mut Cursor::new(file))
+            .into_iter()
+            .map(|t| (t.source, t.bpm))
+            .collect()
+    }
+
+    #[test]
+    fn id3() {
+        let mut serato =
+            b"\x00application/octet-stream\x00\x00Serato Autotags\x00\x01\x01".to_vec();
+        serato.extend(b"71.50\x00-3.257\x000.000\x00");
+        let tag = id3_tag(&[
+            id3_frame("TBPM", b"\x00143"),
+            // utf-16, with a byte order mark
+            id3_frame(
+                "TXXX",
+                b"\x01\xff\xfeT\x00E\x00M\x00P\x00O\x00\x00\x00\xff\xfe1\x004\x003\x00.\x005\x00",
+            ),
+            id3_frame("TXXX", b"\x00MOOD\x00happy"),
+            id3_frame("GEOB", &serato),
+        ]);
+        assert_eq!(
+            tempos(tag),
+            vec![
+                (TagSource::Id3Tbpm, 143.0),
+                (TagSource::Id3Txxx("TEMPO".to_string()), 143.5),
+                (TagSource::Serato, 71.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn mp4() {
+        let data = atom(b"data", &[0, 0, 0, 21, 0, 0, 0, 0, 0, 184]);
+        let ilst = atom(b"ilst", &atom(b"tmpo", &data));
+        let meta = atom(
+            b"meta",
+            &[&[0u8, 0, 0, 0][..], &atom(b"hdlr", &[0; 25]), &ilst].concat(),
+        );
+        let moov = atom(
+            b"moov",
+            &[atom(b"mvhd", &[0; 100]), atom(b"udta", &meta)].concat(),
+        );
+        let file = [
+            atom(b"ftyp", b"M4A \x00\x00\x00\x00"),
+            atom(b"mdat", &[0; 1000]),
+            moov,
+        ]
+        .concat();
+        assert_eq!(tempos(file), vec![(TagSource::Mp4Tmpo, 184.0)]);
+
+        // atoms that claim to be larger than the file are ignored, as are 64
+        // bit sizes that would overflow the position of the next atom
+        let ftyp = atom(b"ftyp", b"M4A \x00\x00\x00\x00");
+        let huge = [&[0xff; 4][..], b"moov"].concat();
+        assert!(tempos([ftyp.clone(), huge].concat()).is_empty());
+        let overflow = [&[0, 0, 0, 1][..], b"mdat", &[0xff; 8]].concat();
+        assert!(tempos([ftyp, overflow].concat()).is_empty());
+    }
+
+    #[test]
+    fn flac() {
+        let serato = "YXBwbGljYXRpb24vb2N0ZXQtc3RyZWFtAABTZXJhdG8gQXV0b3RhZ3MAAQExMjAuMDAALTMuMjU3ADAuMDAwAA==";
+        let comments = vorbis_comments(&[
+            "TITLE=Shiny Stockings",
+            "bpm=121,5",
+            &format!("SERATO_AUTOTAGS={}", serato),
+        ]);
+        let mut file = b"fLaC".to_vec();
+        // a (non-last) streaminfo block, then the (last) vorbis comment block
+        file.extend(&[0, 0, 0, 34]);
+        file.extend(&[0u8; 34]);
+        file.extend(&[0x84, 0, 0, comments.len() as u8]);
+        file.extend(comments);
+        assert_eq!(
+            tempos(file),
+            vec![(TagSource::VorbisBpm, 121.5), (TagSource::Serato, 120.0)]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        // a tag that claims to be (much) larger than the file is read as far as it goes
+        let mut tag = id3_tag(&[id3_frame("TBPM", b"\x00143")]);
+        tag[6..10].copy_from_slice(&[0x7f; 4]);
+        assert_eq!(tempos(tag), vec![(TagSource::Id3Tbpm, 143.0)]);
+    }
+
+    #[test]
+    fn nothing() {
+        assert!(tempos(b"RIFF\x00\x00\x00\x00WAVE".to_vec()).is_empty());
+        assert!(tempos(id3_tag(&[id3_frame("TBPM", b"\x00fast")])).is_empty());
+    }
+}
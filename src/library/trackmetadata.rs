@@ -1,5 +1,6 @@
 use library::ellingtondata::*;
-use std::collections::BTreeMap;
+use library::tags::*;
+use std::cmp::Reverse;
 use std::path::Path;
 
 use talamel::*;
@@ -10,47 +11,71 @@ pub struct TrackMetadata {
     pub name: String,                  // we must always have a track name
     pub bpm: Option<i64>,              // we might not have a bpm value
     pub comments: Option<Vec<String>>, // or comments!
+    // every tempo stored in the tags, the most trustworthy first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tempos: Vec<TagTempo>,
 }
 
 impl TrackMetadata {
+    // Read the ellington data from a comment, or title, returning any errors
+    // that weren't from the data simply being missing.
+    fn parse_metadata(text: &str, mode: ParseMode) -> (EllingtonData, Vec<ParseError>) {
+        match EllingtonData::parse_with(text, mode) {
+            Ok((ed, errors)) => {
+                info!("Found ellington metadata: {:?}", ed);
+                (ed, errors)
+            }
+            Err(ParseError {
+                reason: ParseReason::NoData,
+                ..
+            }) => {
+                info!("No ellington data found in: {:?}", text);
+                (EllingtonData::empty(), vec![])
+            }
+            Err(e) => (EllingtonData::empty(), vec![e]),
+        }
+    }
+
     #[flame("TrackMetadata")]
-    pub fn comment_metadata(self: &Self) -> EllingtonData {
-        // initialise our array
-        let mut algs: BTreeMap<Algorithm, Bpm> = BTreeMap::new();
-        // match the comments, and iterate over them, appending them to "data"
+    pub fn comment_metadata(self: &Self, mode: ParseMode) -> (EllingtonData, Vec<ParseError>) {
+        let mut data = EllingtonData::empty();
+        let mut errors = vec![];
+        // match the comments, and iterate over them, combining their data (with
+        // later comments taking precedence, unless they have an "na" for a tempo)
         match &self.comments {
             Some(v) => {
                 for c in v {
-                    // parse the comment into some ellington data
-                    match EllingtonData::parse(&c) {
-                        Some(mut ed) => {
-                            info!("Found ellington metadata: {:?}", ed);
-                            // There _will_ be a bug here if we accidentally insert "na" after a good value.
-                            // Move this into the ellington data + method?
-                            algs.append(&mut ed.algs);
-                        }
-                        None => info!("No ellington data found in comment: {:?}", c),
-                    };
+                    let (ed, mut e) = Self::parse_metadata(c, mode);
+                    data = ed.merge(data, MergePolicy::PreferNonNA);
+                    errors.append(&mut e);
                 }
             }
             None => info!("Got no comments from metadata."),
         };
-        EllingtonData { algs: algs }
+        (data, errors)
     }
 
     #[flame("TrackMetadata")]
-    pub fn title_metadata(self: &Self) -> EllingtonData {
-        // initialise our array
-        let mut algs: BTreeMap<Algorithm, Bpm> = BTreeMap::new();
+    pub fn title_metadata(self: &Self, mode: ParseMode) -> (EllingtonData, Vec<ParseError>) {
         // check the track name (title) to see if it has metadata
-        match EllingtonData::parse(&self.name) {
-            Some(mut ed) => {
-                info!("Found ellington metadata: {:?}", ed);
-                algs.append(&mut ed.algs);
-            }
-            None => info!("No ellington data found in title: {:?}", self.name),
+        Self::parse_metadata(&self.name, mode)
+    }
+
+    // The most trustworthy tempo in the tags of the track
+    pub fn tag_tempo(&self) -> Option<&TagTempo> {
+        self.tempos.first()
+    }
+
+    // The tempos in the tags that disagree with the most trustworthy one
+    pub fn tempo_conflicts(&self) -> Vec<&TagTempo> {
+        match self.tag_tempo() {
+            Some(best) => self
+                .tempos
+                .iter()
+                .filter(|t| (t.bpm - best.bpm).abs() >= 0.5)
+                .collect(),
+            None => vec![],
         }
-        EllingtonData { algs: algs }
     }
 
     #[flame("TrackMetadata")]
@@ -59,13 +84,27 @@ impl TrackMetadata {
         let tf = TalamelFile::new(location).ok()?;
 
         let name = tf.title().ok()?;
-        let bpm = tf.bpm().map(|b| b as i64);
         let comments = tf.comments().ok();
 
+        // taglib reads formats that we don't, so fall back to it. It only gives an
+        // integer tempo, which would look like a conflict with an exact one that
+        // we read ourselves, so only use it when we found none.
+        let mut tempos = read_tempos(location);
+        if let Some(b) = tf.bpm().filter(|b| *b > 0 && tempos.is_empty()) {
+            tempos.push(TagTempo {
+                source: TagSource::Taglib,
+                bpm: b as f64,
+            });
+        }
+        tempos.sort_by_key(|t| Reverse(t.source.trust()));
+        debug!("Tempos in tags: {:?}", tempos);
+        let bpm = tempos.first().map(|t| t.bpm.round() as i64);
+
         Some(TrackMetadata {
             name: name,
             bpm: bpm,
             comments: comments,
+            tempos,
         })
     }
 }
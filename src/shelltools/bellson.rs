@@ -1,21 +1,31 @@
 use super::generic::*;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct BellsonCommand {
     pub path: EscapedFilename,
+    pub timeout: Duration,
 }
 
 impl BellsonCommand {
     pub fn default(path: &PathBuf) -> BellsonCommand {
         BellsonCommand {
             path: EscapedFilename::new(path),
+            // loading the model is slow, but a track should never take this long
+            timeout: Duration::from_secs(600),
         }
     }
 }
 
 impl ShellProgram for BellsonCommand {
-    const COMMAND_NAME: &'static str = "bellson-infer";
+    fn command_name(&self) -> &str {
+        "bellson-infer"
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        Some(self.timeout)
+    }
 
     fn as_args(self: &Self) -> Vec<String> {
         vec![self.path.filename.as_str()]
@@ -29,7 +29,9 @@ impl BpmCall {
 
 impl ShellProgram for BpmCall {
     // update this for whatever system we're on
-    const COMMAND_NAME: &'static str = "tools/bpm-tools/bpm";
+    fn command_name(&self) -> &str {
+        "tools/bpm-tools/bpm"
+    }
 
     fn as_args(self: &BpmCall) -> Vec<String> {
         vec![
@@ -104,7 +104,10 @@ impl FfmpegCommand {
 
     pub fn spawn<'a>(self: &Self) -> Result<Child> {
         // let child =
-        self.call().stdout(Stdio::piped()).spawn()
+        self.call()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
         // .expect("Failed to execute standalone ffmpeg call");
 
         // child
@@ -114,7 +117,9 @@ impl FfmpegCommand {
 }
 
 impl ShellProgram for FfmpegCommand {
-    const COMMAND_NAME: &'static str = "ffmpeg";
+    fn command_name(&self) -> &str {
+        "ffmpeg"
+    }
 
     fn as_args(self: &Self) -> Vec<String> {
         vec![
@@ -143,3 +148,44 @@ impl ShellProgram for FfmpegCommand {
         .collect()
     }
 }
+
+// Measures the loudness of a file with ffmpeg's ebur128 filter, which (unlike
+// the mono stream that we decode) weights and sums each of its channels. The
+// filter writes a summary, with the integrated loudness and sample peak, to
+// stderr.
+#[derive(Debug)]
+pub struct FfmpegLoudnessCommand {
+    pub filename: EscapedFilename,
+}
+
+impl FfmpegLoudnessCommand {
+    pub fn new(filename: &PathBuf) -> FfmpegLoudnessCommand {
+        FfmpegLoudnessCommand {
+            filename: EscapedFilename::new(filename),
+        }
+    }
+}
+
+impl ShellProgram for FfmpegLoudnessCommand {
+    fn command_name(&self) -> &str {
+        "ffmpeg"
+    }
+
+    fn as_args(&self) -> Vec<String> {
+        [
+            "-hide_banner",
+            "-nostats",
+            "-i",
+            self.filename.filename.as_str(),
+            "-filter_complex",
+            "ebur128=peak=sample",
+            // we only want the summary, so throw the audio away
+            "-f",
+            "null",
+            "-",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+}
@@ -0,0 +1,39 @@
+use super::generic::*;
+use std::path::PathBuf;
+
+// Asks ffprobe for the format and (first) audio stream of a file, as JSON
+#[derive(Debug)]
+pub struct FfprobeCommand {
+    pub filename: EscapedFilename,
+}
+
+impl FfprobeCommand {
+    pub fn new(filename: &PathBuf) -> FfprobeCommand {
+        FfprobeCommand {
+            filename: EscapedFilename::new(filename),
+        }
+    }
+}
+
+impl ShellProgram for FfprobeCommand {
+    fn command_name(&self) -> &str {
+        "ffprobe"
+    }
+
+    fn as_args(&self) -> Vec<String> {
+        [
+            "-v",
+            "quiet",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            "-select_streams",
+            "a:0",
+            self.filename.filename.as_str(),
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+}
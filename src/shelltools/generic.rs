@@ -1,5 +1,10 @@
+use std::fmt;
+use std::io::{self, Read};
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct EscapedFilename {
@@ -28,42 +33,205 @@ pub trait ShellArg {
     fn value(&self) -> &'static str;
 }
 
+// The ways in which running a program can fail
+#[derive(Debug)]
+pub enum ShellError {
+    Missing(String), // the program could not be found
+    Io(io::Error),   // the program could not be started, or talked to
+    Failed {
+        status: ExitStatus, // the program exited unsuccessfully, or was killed by a signal
+        stderr: String,
+    },
+    TimedOut(Duration), // the program was killed after running for this long
+}
+
+impl ShellError {
+    // whether running the program again might succeed
+    pub fn retryable(&self) -> bool {
+        match self {
+            ShellError::Failed { .. } | ShellError::TimedOut(_) => true,
+            ShellError::Missing(_) | ShellError::Io(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShellError::Missing(program) => write!(f, "could not find program {}", program),
+            ShellError::Io(e) => write!(f, "io error {}", e),
+            ShellError::Failed { status, stderr } => {
+                write!(f, "exited with {}: {}", status, stderr.trim())
+            }
+            ShellError::TimedOut(timeout) => write!(f, "timed out after {:?}", timeout),
+        }
+    }
+}
+
+// Read everything from a pipe on another thread, so that a program can't
+// block on writing to a full pipe while we wait for it.
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some(mut p) = pipe {
+            let _ = p.read_to_end(&mut bytes);
+        }
+        let _ = sender.send(String::from_utf8_lossy(&bytes).to_string());
+    });
+    receiver
+}
+
+// Run programs in their own process group, so that we can kill anything that
+// they start along with them.
+#[cfg(unix)]
+fn isolate(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn isolate(_command: &mut Command) {}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    // the group has the id of the child, and outlives it while anything it
+    // started is still running
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// Wait for a child to exit, killing it (and anything it started) if it runs
+// for longer than the timeout. Anything that it started may also hold its
+// pipes open after it exits, so we wait for its output until the timeout too.
+fn wait(
+    child: &mut Child,
+    output: &[Receiver<String>],
+    timeout: Option<Duration>,
+) -> Result<(ExitStatus, Vec<String>), ShellError> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => {
+            let status = child.wait().map_err(ShellError::Io)?;
+            let output = output.iter().map(|o| o.recv().unwrap_or_default());
+            return Ok((status, output.collect()));
+        }
+    };
+    let start = Instant::now();
+    let remaining = || timeout.checked_sub(start.elapsed()).unwrap_or_default();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(ShellError::Io)? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            kill(child);
+            return Err(ShellError::TimedOut(timeout));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let mut collected = vec![];
+    for o in output {
+        match o.recv_timeout(remaining()) {
+            Ok(text) => collected.push(text),
+            Err(mpsc::RecvTimeoutError::Disconnected) => collected.push(String::new()),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                kill(child);
+                return Err(ShellError::TimedOut(timeout));
+            }
+        }
+    }
+    Ok((status, collected))
+}
+
 // and a trait for command line programs
 pub trait ShellProgram {
-    const COMMAND_NAME: &'static str;
+    fn command_name(&self) -> &str;
 
     fn as_args(&self) -> Vec<String>;
 
-    // the below are defined almost entirely in terms of as_args and COMMAND_NAME, so don't need to be defined specially for each program
+    // the below are defined almost entirely in terms of as_args and command_name, so don't need to be defined specially for each program
 
     fn as_shell_args(&self) -> Vec<String> {
-        let mut args = vec![Self::COMMAND_NAME.to_string()];
+        let mut args = vec![self.command_name().to_string()];
         args.append(&mut self.as_args());
         return args;
     }
 
     fn call(&self) -> Command {
-        let mut command = Command::new(Self::COMMAND_NAME);
+        let mut command = Command::new(self.command_name());
         command.args(self.as_args());
         return command;
     }
 
-    fn run(&self) -> Option<(String, String)> {
-        match self.call().output() {
-            Ok(output) => {
-                info!(
-                    "Exit status of {:?} = {:?}",
-                    self.as_shell_args(),
-                    output.status
-                );
-                Some((
-                    String::from_utf8_lossy(&output.stdout).to_string(),
-                    String::from_utf8_lossy(&output.stderr).to_string(),
-                ))
+    // how long to let the program run before killing it, if at all
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    // how many times to re-run the program if it fails, or times out
+    fn retries(&self) -> u32 {
+        0
+    }
+
+    // run the program once, returning its stdout and stderr if it succeeds
+    fn execute_once(&self) -> Result<(String, String), ShellError> {
+        let mut command = self.call();
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        isolate(&mut command);
+        let mut child = command.spawn().map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => ShellError::Missing(self.command_name().to_string()),
+            _ => ShellError::Io(e),
+        })?;
+        let output = [drain(child.stdout.take()), drain(child.stderr.take())];
+        let (status, mut output) = wait(&mut child, &output, self.timeout())?;
+        info!("Exit status of {:?} = {:?}", self.as_shell_args(), status);
+        let stderr = output.pop().unwrap_or_default();
+        let stdout = output.pop().unwrap_or_default();
+        if status.success() {
+            Ok((stdout, stderr))
+        } else {
+            Err(ShellError::Failed { status, stderr })
+        }
+    }
+
+    // run the program, retrying it (if configured to) when it fails
+    fn execute(&self) -> Result<(String, String), ShellError> {
+        let mut attempt = 0;
+        loop {
+            match self.execute_once() {
+                Err(ref e) if e.retryable() && attempt < self.retries() => {
+                    attempt += 1;
+                    info!(
+                        "Command {:?} {}, retrying ({}/{})",
+                        self.as_shell_args(),
+                        e,
+                        attempt,
+                        self.retries()
+                    );
+                }
+                result => return result,
             }
+        }
+    }
+
+    fn run(&self) -> Option<(String, String)> {
+        match self.execute() {
+            Ok(output) => Some(output),
             Err(e) => {
                 error!(
-                    "Got error {:?} while running command {:?}",
+                    "Got error \"{}\" while running command {:?}",
                     e,
                     self.as_shell_args()
                 );
@@ -72,3 +240,121 @@ pub trait ShellProgram {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    struct Script {
+        script: String,
+        timeout: Option<Duration>,
+        retries: u32,
+    }
+
+    impl Script {
+        fn new(script: &str) -> Script {
+            Script {
+                script: script.to_string(),
+                timeout: None,
+                retries: 0,
+            }
+        }
+    }
+
+    impl ShellProgram for Script {
+        fn command_name(&self) -> &str {
+            "sh"
+        }
+
+        fn as_args(&self) -> Vec<String> {
+            vec!["-c".to_string(), self.script.clone()]
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            self.timeout
+        }
+
+        fn retries(&self) -> u32 {
+            self.retries
+        }
+    }
+
+    struct Missing {}
+
+    impl ShellProgram for Missing {
+        fn command_name(&self) -> &str {
+            "ellington-nonexistent-program"
+        }
+
+        fn as_args(&self) -> Vec<String> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn success() {
+        let (stdout, stderr) = Script::new("echo out; echo err >&2").execute().unwrap();
+        assert_eq!(stdout, "out\n");
+        assert_eq!(stderr, "err\n");
+    }
+
+    #[test]
+    fn errors() {
+        match Script::new("echo garbage; echo broken >&2; exit 3").execute() {
+            Err(ShellError::Failed { status, stderr }) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(stderr, "broken\n");
+            }
+            result => panic!("Expected failure, got {:?}", result),
+        }
+        match (Missing {}).execute() {
+            Err(ShellError::Missing(program)) => {
+                assert_eq!(program, "ellington-nonexistent-program")
+            }
+            result => panic!("Expected missing program, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let mut script = Script::new("sleep 10");
+        script.timeout = Some(Duration::from_millis(100));
+        let start = Instant::now();
+        match script.execute() {
+            Err(ShellError::TimedOut(_)) => {}
+            result => panic!("Expected timeout, got {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn timeout_kills_children() {
+        // the program exits, but leaves something running that holds its pipes
+        let mut script = Script::new("sleep 10 & echo started");
+        script.timeout = Some(Duration::from_millis(500));
+        let start = Instant::now();
+        match script.execute() {
+            Err(ShellError::TimedOut(_)) => {}
+            result => panic!("Expected timeout, got {:?}", result),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retries() {
+        // fails the first time that it's run, and succeeds the second
+        let marker = env::temp_dir().join(format!("ellington-retry-{}", process::id()));
+        let _ = fs::remove_file(&marker);
+        let script = format!(
+            "if [ -e {0} ]; then echo ok; else touch {0}; exit 1; fi",
+            marker.to_str().unwrap()
+        );
+        let mut program = Script::new(&script);
+        assert!(program.execute().is_err());
+        let _ = fs::remove_file(&marker);
+        program.retries = 1;
+        assert_eq!(program.execute().unwrap().0, "ok\n");
+        let _ = fs::remove_file(&marker);
+    }
+}
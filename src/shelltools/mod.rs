@@ -1,6 +1,9 @@
 pub mod bellson;
 pub mod bpm;
 pub mod ffmpeg;
+pub mod ffprobe;
 pub mod generic;
 pub mod pipe;
 pub mod sox;
+pub mod template;
+pub mod worker;
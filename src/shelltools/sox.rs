@@ -1,4 +1,5 @@
 use super::generic::*;
+use std::io::Result;
 use std::path::PathBuf;
 use std::process::Child;
 
@@ -109,6 +110,13 @@ impl SoxCommand {
         }
     }
 
+    pub fn spawn(&self) -> Result<Child> {
+        self.call()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+    }
+
     pub fn run<'a>(self: &Self) -> Child {
         let child = self
             .call()
@@ -123,7 +131,9 @@ impl SoxCommand {
 }
 
 impl ShellProgram for SoxCommand {
-    const COMMAND_NAME: &'static str = "sox";
+    fn command_name(&self) -> &str {
+        "sox"
+    }
 
     fn as_args(self: &Self) -> Vec<String> {
         vec![
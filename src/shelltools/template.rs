@@ -0,0 +1,88 @@
+use super::generic::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// A command built from a template, such as ["aubio", "tempo", "{file}"], where
+// the placeholder is replaced with the name of the file being processed.
+#[derive(Debug)]
+pub struct TemplateCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+impl TemplateCommand {
+    pub const FILE_PLACEHOLDER: &'static str = "{file}";
+
+    // Programs are given their arguments as strings, so there's no command for
+    // a path that isn't valid unicode.
+    pub fn new(template: &[String], path: &PathBuf) -> Option<TemplateCommand> {
+        let filename = match path.to_str() {
+            Some(filename) => filename,
+            None => {
+                error!(
+                    "Cannot pass the path {:?} to {:?}, as it is not valid unicode",
+                    path,
+                    template.first()
+                );
+                return None;
+            }
+        };
+        let mut parts = template
+            .iter()
+            .map(|part| part.replace(Self::FILE_PLACEHOLDER, filename));
+        Some(TemplateCommand {
+            program: parts.next()?,
+            args: parts.collect(),
+            timeout: None,
+            retries: 0,
+        })
+    }
+}
+
+impl ShellProgram for TemplateCommand {
+    fn command_name(&self) -> &str {
+        self.program.as_str()
+    }
+
+    fn as_args(&self) -> Vec<String> {
+        self.args.clone()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholder() {
+        let template = vec![
+            "aubio".to_string(),
+            "tempo".to_string(),
+            "{file}".to_string(),
+        ];
+        let call = TemplateCommand::new(&template, &PathBuf::from("a track.mp3")).unwrap();
+        assert_eq!(call.command_name(), "aubio");
+        assert_eq!(call.as_args(), vec!["tempo", "a track.mp3"]);
+        assert!(TemplateCommand::new(&[], &PathBuf::from("track.mp3")).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_unicode_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(OsStr::from_bytes(b"track\xff.m4p"));
+        assert!(TemplateCommand::new(&["aubio".to_string()], &path).is_none());
+    }
+}
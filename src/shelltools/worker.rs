@@ -0,0 +1,281 @@
+/*
+    A protocol for long-running estimator processes ("workers"), so that
+    expensive models (e.g. bellson's neural network) are loaded once, and
+    reused for every track, rather than once per track.
+
+    Workers speak line-delimited JSON over stdin/stdout:
+
+    - On startup (e.g. once their model is loaded) a worker writes a
+      handshake line: {"ready": true, "protocol": 1}
+    - Ellington then writes one request per line: {"id": 1, "file": "/path/to/track.mp3"}
+    - The worker replies with one line per request, either with a tempo:
+      {"id": 1, "bpm": 143.2, "confidence": 0.8}  (confidence is optional)
+      or with an error: {"id": 1, "error": "could not decode audio"}
+
+    Anything a worker writes to stderr is passed through. If a worker takes
+    too long to respond it is killed, and if it dies it is restarted for the
+    next request.
+*/
+use serde_json;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerConfig {
+    pub command: Vec<String>, // the command (and arguments) that starts the worker
+    pub startup_timeout: f64, // how long (in seconds) to wait for the handshake
+    pub request_timeout: f64, // how long (in seconds) to wait for each response
+    pub max_restarts: u32,    // how many times to restart a worker that has died, or been killed
+}
+
+impl WorkerConfig {
+    pub fn new(command: Vec<String>) -> WorkerConfig {
+        WorkerConfig {
+            command,
+            startup_timeout: 120.0,
+            request_timeout: 60.0,
+            max_restarts: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Handshake {
+    ready: bool,
+    protocol: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct WorkerRequest<'a> {
+    id: u64,
+    file: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkerResponse {
+    pub id: u64,
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    #[serde(default)]
+    pub confidence: Option<f64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+// The ways in which a request to a worker can fail
+enum Failure {
+    Unreachable, // the worker had died before we sent the request
+    Died,        // the worker died while processing the request
+    TimedOut,    // the worker didn't respond in time
+}
+
+impl From<RecvTimeoutError> for Failure {
+    fn from(e: RecvTimeoutError) -> Failure {
+        match e {
+            RecvTimeoutError::Timeout => Failure::TimedOut,
+            RecvTimeoutError::Disconnected => Failure::Died,
+        }
+    }
+}
+
+// A running worker process, with a thread forwarding its output lines.
+struct WorkerProcess {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+}
+
+impl WorkerProcess {
+    fn spawn(config: &WorkerConfig) -> Option<WorkerProcess> {
+        let (program, args) = config.command.split_first()?;
+        let mut child = match Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                error!(
+                    "Got error {:?} while starting worker {:?}",
+                    e, config.command
+                );
+                return None;
+            }
+        };
+        let stdin = child.stdin.take()?;
+        let stdout = child.stdout.take()?;
+
+        let (sender, lines) = channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(l) => {
+                        if sender.send(l).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Some(WorkerProcess {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    // Wait for a (non-empty) line from the worker
+    fn receive(&self, timeout: f64) -> Result<String, RecvTimeoutError> {
+        loop {
+            let line = self
+                .lines
+                .recv_timeout(Duration::from_millis((timeout * 1000.0) as u64))?;
+            if !line.trim().is_empty() {
+                return Ok(line);
+            }
+        }
+    }
+
+    fn handshake(&self, timeout: f64) -> Option<()> {
+        let line = match self.receive(timeout) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Worker did not complete handshake: {:?}", e);
+                return None;
+            }
+        };
+        match serde_json::from_str::<Handshake>(&line) {
+            Ok(Handshake {
+                ready: true,
+                protocol,
+            }) if protocol == PROTOCOL_VERSION => Some(()),
+            Ok(handshake) => {
+                error!("Worker sent an incompatible handshake: {:?}", handshake);
+                None
+            }
+            Err(e) => {
+                error!("Could not parse worker handshake {:?}: {:?}", line, e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for WorkerProcess {
+    fn drop(&mut self) {
+        // Make sure that the worker doesn't outlive us
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Worker {
+    pub config: WorkerConfig,
+    process: Option<WorkerProcess>,
+    next_id: u64,
+    started: bool,
+    restarts: u32,
+}
+
+impl Worker {
+    pub fn new(config: WorkerConfig) -> Worker {
+        Worker {
+            config,
+            process: None,
+            next_id: 0,
+            started: false,
+            restarts: 0,
+        }
+    }
+
+    // Start the worker if it isn't running, returning false if it can't be started.
+    fn ensure_started(&mut self) -> bool {
+        if self.process.is_some() {
+            return true;
+        }
+        if self.started {
+            if self.restarts >= self.config.max_restarts {
+                error!("Worker {:?} has died too many times", self.config.command);
+                return false;
+            }
+            self.restarts += 1;
+            info!(
+                "Restarting worker {:?} ({}/{})",
+                self.config.command, self.restarts, self.config.max_restarts
+            );
+        }
+        self.started = true;
+        info!("Starting worker {:?}", self.config.command);
+        self.process = WorkerProcess::spawn(&self.config).and_then(|process| {
+            process.handshake(self.config.startup_timeout)?;
+            Some(process)
+        });
+        self.process.is_some()
+    }
+
+    // Send a request to the worker, and wait for its response
+    fn send(&mut self, file: &str) -> Result<WorkerResponse, Failure> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let timeout = self.config.request_timeout;
+        let process = self.process.as_mut().ok_or(Failure::Unreachable)?;
+
+        let request = serde_json::to_string(&WorkerRequest { id, file }).unwrap();
+        debug!("Sending request to worker: {}", request);
+        if writeln!(process.stdin, "{}", request)
+            .and_then(|_| process.stdin.flush())
+            .is_err()
+        {
+            return Err(Failure::Unreachable);
+        }
+
+        loop {
+            let line = process.receive(timeout)?;
+            debug!("Got response from worker: {}", line);
+            match serde_json::from_str::<WorkerResponse>(&line) {
+                Ok(response) if response.id == id => return Ok(response),
+                Ok(response) => info!("Ignoring stale worker response: {:?}", response),
+                Err(e) => error!("Could not parse worker response {:?}: {:?}", line, e),
+            }
+        }
+    }
+
+    // Ask the worker to estimate the tempo of a file, (re)starting it if needed.
+    // If the worker dies (or hangs) while processing a file, we give up on that
+    // file, and restart the worker for the next one.
+    pub fn request(&mut self, audio_file: &Path) -> Option<WorkerResponse> {
+        let file = audio_file.to_str()?;
+        loop {
+            if !self.ensure_started() {
+                return None;
+            }
+            match self.send(file) {
+                Ok(response) => return Some(response),
+                Err(Failure::Unreachable) => {
+                    info!("Worker has died since the last request");
+                    self.process = None;
+                }
+                Err(Failure::Died) => {
+                    error!("Worker died while processing {:?}", audio_file);
+                    self.process = None;
+                    return None;
+                }
+                Err(Failure::TimedOut) => {
+                    // A wedged worker can't be trusted with the next request either.
+                    error!("Worker timed out on {:?}, killing it", audio_file);
+                    self.process = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+/*
+    Synthetic audio with a known tempo (and swing, and meter), so that the
+    estimators and analysers can be tested against ground truth, without
+    needing any recordings. A piece of audio is described by a list of hits
+    (percussive sounds at given times), which is then rendered to samples,
+    and (optionally) written to a WAV file.
+*/
+pub mod wav;
+
+use estimators::SAMPLE_RATE;
+use std::f64::consts::PI;
+
+// The percussive sounds that we can render
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Voice {
+    Click, // a short decaying 1KHz tone
+    Kick,  // a low, falling, tone
+    Snare, // a burst of noise, over a mid tone
+    Ride,  // a long, bright, ringing burst of noise
+}
+
+impl Voice {
+    // How long (in seconds) the sound lasts before it is inaudible
+    fn length(&self) -> f64 {
+        match self {
+            Voice::Click => 0.05,
+            Voice::Kick => 0.3,
+            Voice::Snare => 0.2,
+            Voice::Ride => 0.6,
+        }
+    }
+
+    // The value of the sound, t seconds after it starts, with some noise in [-1, 1]
+    fn value(&self, t: f64, noise: f64) -> f64 {
+        match self {
+            Voice::Click => (2.0 * PI * 1000.0 * t).sin() * (-t * 200.0).exp(),
+            Voice::Kick => {
+                let phase = 2.0 * PI * (50.0 * t + 70.0 * (1.0 - (-t * 20.0).exp()) / 20.0);
+                phase.sin() * (-t * 15.0).exp()
+            }
+            Voice::Snare => (0.7 * noise + 0.3 * (2.0 * PI * 200.0 * t).sin()) * (-t * 30.0).exp(),
+            Voice::Ride => (0.5 * noise + 0.2 * (2.0 * PI * 5200.0 * t).sin()) * (-t * 8.0).exp(),
+        }
+    }
+}
+
+// A sound, at a time (in seconds), with a gain in [0, 1]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub time: f64,
+    pub voice: Voice,
+    pub gain: f64,
+}
+
+impl Hit {
+    pub fn new(time: f64, voice: Voice, gain: f64) -> Hit {
+        Hit { time, voice, gain }
+    }
+}
+
+// A (deterministic) source of white noise, so that renders are repeatable
+struct Noise {
+    state: u64,
+}
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let value = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        value as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+// Render a list of hits to (at most) the given number of seconds of (mono) samples
+pub fn render(hits: &[Hit], seconds: f64) -> Vec<f32> {
+    let mut samples = vec![0.0f64; (seconds * SAMPLE_RATE) as usize];
+    let mut noise = Noise {
+        state: 0x9e37_79b9_7f4a_7c15,
+    };
+    for hit in hits {
+        let start = (hit.time * SAMPLE_RATE).round().max(0.0) as usize;
+        let end = (start + (hit.voice.length() * SAMPLE_RATE) as usize).min(samples.len());
+        for (i, sample) in samples.iter_mut().enumerate().take(end).skip(start) {
+            let t = (i - start) as f64 / SAMPLE_RATE;
+            *sample += hit.gain * hit.voice.value(t, noise.next());
+        }
+    }
+    samples.iter().map(|s| s.clamp(-1.0, 1.0) as f32).collect()
+}
+
+// The times of the beats at a steady tempo
+fn beats(bpm: f64, seconds: f64) -> impl Iterator<Item = f64> {
+    let period = 60.0 / bpm;
+    (0..)
+        .map(move |beat| beat as f64 * period)
+        .take_while(move |time| *time < seconds)
+}
+
+// A click on every beat
+pub fn clicks(bpm: f64, seconds: f64) -> Vec<Hit> {
+    beats(bpm, seconds)
+        .map(|time| Hit::new(time, Voice::Click, 1.0))
+        .collect()
+}
+
+// A click on every beat, with an accent on the first beat of every bar
+pub fn accented_clicks(bpm: f64, beats_per_bar: usize, seconds: f64) -> Vec<Hit> {
+    beats(bpm, seconds)
+        .enumerate()
+        .map(|(beat, time)| {
+            let gain = match beat % beats_per_bar {
+                0 => 1.0,
+                _ => 0.3,
+            };
+            Hit::new(time, Voice::Click, gain)
+        })
+        .collect()
+}
+
+// A click on every beat, with quieter clicks on the offbeats, which are swung
+// so that the ratio of the first to second half of each beat is the given ratio
+pub fn swung_clicks(bpm: f64, ratio: f64, seconds: f64) -> Vec<Hit> {
+    let offbeat = 60.0 / bpm * ratio / (1.0 + ratio);
+    beats(bpm, seconds)
+        .flat_map(|time| {
+            vec![
+                Hit::new(time, Voice::Click, 1.0),
+                Hit::new(time + offbeat, Voice::Click, 0.5),
+            ]
+        })
+        .filter(|hit| hit.time < seconds)
+        .collect()
+}
+
+// A swing drum pattern: the ride plays "ding, ding-a ding", with the (swung)
+// skip notes before beats 2 and 4, the kick is feathered on every beat, and
+// the snare (or hi-hat) is on 2 and 4.
+pub fn swing_pattern(bpm: f64, ratio: f64, seconds: f64) -> Vec<Hit> {
+    let offbeat = 60.0 / bpm * ratio / (1.0 + ratio);
+    let mut hits = vec![];
+    for (beat, time) in beats(bpm, seconds).enumerate() {
+        hits.push(Hit::new(time, Voice::Ride, 0.6));
+        hits.push(Hit::new(time, Voice::Kick, 0.4));
+        match beat % 2 {
+            0 => hits.push(Hit::new(time + offbeat, Voice::Ride, 0.3)),
+            _ => hits.push(Hit::new(time, Voice::Snare, 0.5)),
+        }
+    }
+    hits.retain(|hit| hit.time < seconds);
+    hits
+}
+
+// A click on every beat, with the tempo changing steadily from start_bpm to end_bpm
+pub fn tempo_ramp(start_bpm: f64, end_bpm: f64, seconds: f64) -> Vec<Hit> {
+    let mut hits = vec![];
+    let mut time = 0.0;
+    while time < seconds {
+        hits.push(Hit::new(time, Voice::Click, 1.0));
+        let bpm = start_bpm + (end_bpm - start_bpm) * time / seconds;
+        time += 60.0 / bpm;
+    }
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores() {
+        assert_eq!(clicks(120.0, 10.0).len(), 20);
+        let swung = swung_clicks(120.0, 2.0, 1.0);
+        assert_eq!(swung.len(), 4);
+        assert!((swung[1].time - 1.0 / 3.0).abs() < 1e-9);
+        let ramp = tempo_ramp(60.0, 120.0, 60.0);
+        let last = ramp[ramp.len() - 1].time - ramp[ramp.len() - 2].time;
+        assert!((ramp[1].time - 1.0).abs() < 1e-9);
+        assert!((last - 0.5).abs() < 0.02, "Last beat took {}", last);
+    }
+
+    #[test]
+    fn renders() {
+        let samples = render(&swing_pattern(140.0, 2.0, 5.0), 5.0);
+        assert_eq!(samples.len(), 5 * 44100);
+        assert!(samples.iter().all(|s| s.abs() <= 1.0));
+        assert!(samples.iter().any(|s| s.abs() > 0.1));
+        assert_eq!(samples, render(&swing_pattern(140.0, 2.0, 5.0), 5.0));
+    }
+}
@@ -0,0 +1,61 @@
+/*
+    A minimal WAV writer, for (mono) samples at the rate that we decode to,
+    stored as 16 bit PCM so that any decoder can read them.
+*/
+use byteorder::{LittleEndian, WriteBytesExt};
+use estimators::SAMPLE_RATE;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+const BITS_PER_SAMPLE: u16 = 16;
+
+pub fn write_to<W: Write>(writer: &mut W, samples: &[f32]) -> io::Result<()> {
+    let rate = SAMPLE_RATE as u32;
+    let block_align = BITS_PER_SAMPLE / 8;
+    let data_length = samples.len() as u32 * u32::from(block_align);
+
+    writer.write_all(b"RIFF")?;
+    writer.write_u32::<LittleEndian>(36 + data_length)?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_u32::<LittleEndian>(16)?; // the length of this chunk
+    writer.write_u16::<LittleEndian>(1)?; // PCM
+    writer.write_u16::<LittleEndian>(1)?; // mono
+    writer.write_u32::<LittleEndian>(rate)?;
+    writer.write_u32::<LittleEndian>(rate * u32::from(block_align))?;
+    writer.write_u16::<LittleEndian>(block_align)?;
+    writer.write_u16::<LittleEndian>(BITS_PER_SAMPLE)?;
+
+    writer.write_all(b"data")?;
+    writer.write_u32::<LittleEndian>(data_length)?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)).round();
+        writer.write_i16::<LittleEndian>(value as i16)?;
+    }
+    writer.flush()
+}
+
+// Write (mono) samples to a WAV file
+pub fn write(path: &Path, samples: &[f32]) -> io::Result<()> {
+    info!("Writing {} samples to {:?}", samples.len(), path);
+    write_to(&mut BufWriter::new(File::create(path)?), samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let mut bytes = vec![];
+        write_to(&mut bytes, &[0.0, 1.0, -1.0]).unwrap();
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &[42, 0, 0, 0]);
+        assert_eq!(&bytes[24..28], &[0x44, 0xac, 0, 0]); // 44100
+        assert_eq!(&bytes[40..44], &[6, 0, 0, 0]);
+        assert_eq!(&bytes[44..], &[0, 0, 0xff, 0x7f, 0x01, 0x80]);
+    }
+}
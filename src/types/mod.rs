@@ -1,62 +1,157 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
 use std::fmt;
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+// The algorithm that produced a tempo. Algorithms that ellington doesn't know
+// (e.g. configured estimators, or those written by newer versions) are kept,
+// by name, as Custom, so that they survive being read and written again.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AlgorithmE {
     Actual,
     Naive,
     Bellson,
+    Ensemble,
+    Onset,
+    Comb,
     Unknown,
+    Custom(String),
 }
 
 impl AlgorithmE {
+    const BUILTIN: [AlgorithmE; 7] = [
+        AlgorithmE::Actual,
+        AlgorithmE::Naive,
+        AlgorithmE::Bellson,
+        AlgorithmE::Ensemble,
+        AlgorithmE::Onset,
+        AlgorithmE::Comb,
+        AlgorithmE::Unknown,
+    ];
+
+    // Find an algorithm from its full name, or its short code. Built in
+    // algorithms are matched regardless of case, anything else is Custom.
     pub fn parse(st: &str) -> AlgorithmE {
-        match st.to_lowercase().chars().next().unwrap() {
-            'a' => AlgorithmE::Actual,
-            'n' => AlgorithmE::Naive,
-            'b' => AlgorithmE::Bellson,
-            _ => AlgorithmE::Unknown,
-        }
+        Self::BUILTIN
+            .iter()
+            .find(|a| st.eq_ignore_ascii_case(a.print()) || st.eq_ignore_ascii_case(a.short_code()))
+            .cloned()
+            .unwrap_or_else(|| AlgorithmE::Custom(st.to_string()))
     }
 
-    pub fn print(&self) -> &'static str {
+    pub fn print(&self) -> &str {
         match self {
             AlgorithmE::Actual => "actual",
             AlgorithmE::Naive => "naive",
             AlgorithmE::Bellson => "bellson",
+            AlgorithmE::Ensemble => "ensemble",
+            AlgorithmE::Onset => "onset",
+            AlgorithmE::Comb => "comb",
             AlgorithmE::Unknown => "unknown",
+            AlgorithmE::Custom(name) => name.as_str(),
+        }
+    }
+
+    // The short code of the algorithm, used in minimal metadata. Custom
+    // algorithms have no short code of their own, so use their name, which
+    // reads back exactly whatever estimators are registered.
+    pub fn short_code(&self) -> &str {
+        match self {
+            AlgorithmE::Actual => "a",
+            AlgorithmE::Naive => "n",
+            AlgorithmE::Bellson => "b",
+            AlgorithmE::Ensemble => "e",
+            AlgorithmE::Onset => "o",
+            AlgorithmE::Comb => "c",
+            AlgorithmE::Unknown => "u",
+            AlgorithmE::Custom(name) => name.as_str(),
         }
     }
 }
 
 impl fmt::Display for AlgorithmE {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            AlgorithmE::Actual => write!(f, "actual"),
-            AlgorithmE::Naive => write!(f, "naive"),
-            AlgorithmE::Bellson => write!(f, "bellson"),
-            AlgorithmE::Unknown => write!(f, "unknown"),
-        }
+        write!(f, "{}", self.print())
+    }
+}
+
+// Algorithms are (also) used as the keys of maps in the library, so they're
+// stored as plain strings: the variant name of built in algorithms (as
+// serde_derive would write them), or the name of custom algorithms. As
+// parse matches built in names regardless of case, these never clash.
+impl Serialize for AlgorithmE {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            AlgorithmE::Actual => "Actual",
+            AlgorithmE::Naive => "Naive",
+            AlgorithmE::Bellson => "Bellson",
+            AlgorithmE::Ensemble => "Ensemble",
+            AlgorithmE::Onset => "Onset",
+            AlgorithmE::Comb => "Comb",
+            AlgorithmE::Unknown => "Unknown",
+            AlgorithmE::Custom(name) => name.as_str(),
+        })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+impl<'de> Deserialize<'de> for AlgorithmE {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<AlgorithmE, D::Error> {
+        Ok(AlgorithmE::parse(&String::deserialize(deserializer)?))
+    }
+}
+
+// A tempo, either whole, or fractional. Fractional tempos are stored as a
+// whole number of (10^-places) beats per minute, e.g. Fractional(1425, 1)
+// is 142.5bpm. They're always normalised (see from_f64), so that every tempo
+// has exactly one representation.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum BpmE {
     Bpm(i64),
+    Fractional(i64, u32),
+    NA,
+}
+
+// Tempos as they're stored, which (e.g. if they've been edited by hand) may
+// not be normalised
+#[derive(Deserialize)]
+#[serde(rename = "BpmE")]
+enum StoredBpmE {
+    Bpm(i64),
+    Fractional(i64, u32),
     NA,
 }
 
+impl<'de> Deserialize<'de> for BpmE {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BpmE, D::Error> {
+        Ok(match StoredBpmE::deserialize(deserializer)? {
+            StoredBpmE::Bpm(b) => BpmE::Bpm(b),
+            StoredBpmE::Fractional(v, places) => BpmE::fractional(v, places),
+            StoredBpmE::NA => BpmE::NA,
+        })
+    }
+}
+
 impl BpmE {
+    // The most decimal places that we store
+    pub const MAX_PRECISION: u32 = 6;
+
     pub fn parse(st: &str) -> BpmE {
         if st == "na" {
-            BpmE::NA
-        } else {
-            match st.parse::<i64>() {
-                Ok(tmpo) => BpmE::Bpm(tmpo),
+            return BpmE::NA;
+        }
+        let (whole, fraction) = match st.find('.') {
+            Some(ix) => (&st[..ix], &st[ix + 1..]),
+            None => (st, ""),
+        };
+        let places = fraction.len() as u32;
+        match format!("{}{}", whole, fraction).parse::<i64>() {
+            Ok(value) if places <= Self::MAX_PRECISION => BpmE::normalise(value, places),
+            _ => match st.parse::<f64>() {
+                Ok(bpm) => BpmE::from_f64(bpm, Self::MAX_PRECISION),
                 Err(e) => {
-                    error!("Got error {:?} while parsing integer from {}", e, st);
+                    error!("Got error {:?} while parsing tempo from {}", e, st);
                     BpmE::NA
                 }
-            }
+            },
         }
     }
 
@@ -66,17 +161,242 @@ impl BpmE {
             None => BpmE::NA,
         }
     }
+
+    // A tempo, rounded to (at most) the given number of decimal places
+    pub fn from_f64(bpm: f64, places: u32) -> BpmE {
+        if !bpm.is_finite() {
+            return BpmE::NA;
+        }
+        let places = places.min(Self::MAX_PRECISION);
+        BpmE::normalise((bpm * 10f64.powi(places as i32)).round() as i64, places)
+    }
+
+    // A tempo of a whole number of (10^-places) bpm, normalised, and rounded
+    // to (at most) MAX_PRECISION places
+    fn fractional(value: i64, places: u32) -> BpmE {
+        if places > Self::MAX_PRECISION {
+            BpmE::normalise(Self::rescale(value, places) as i64, Self::MAX_PRECISION)
+        } else {
+            BpmE::normalise(value, places)
+        }
+    }
+
+    // Drop any trailing zeros from a fractional tempo
+    fn normalise(mut value: i64, mut places: u32) -> BpmE {
+        while places > 0 && value % 10 == 0 {
+            value /= 10;
+            places -= 1;
+        }
+        match places {
+            0 => BpmE::Bpm(value),
+            _ => BpmE::Fractional(value, places),
+        }
+    }
+
+    pub fn value(&self) -> Option<f64> {
+        match self {
+            BpmE::Bpm(b) => Some(*b as f64),
+            BpmE::Fractional(v, places) => Some(*v as f64 / 10f64.powi(*places as i32)),
+            BpmE::NA => None,
+        }
+    }
+
+    // The tempo, rounded to (at most) the given number of decimal places
+    pub fn round(&self, places: u32) -> BpmE {
+        match self {
+            BpmE::Fractional(_, p) if *p > places => BpmE::from_f64(self.value().unwrap(), places),
+            _ => self.clone(),
+        }
+    }
+
+    // The tempo as a whole number of 10^-MAX_PRECISION bpm, for comparisons
+    fn scaled(&self) -> Option<i128> {
+        match self {
+            BpmE::Bpm(b) => Some(Self::rescale(*b, 0)),
+            BpmE::Fractional(v, places) => Some(Self::rescale(*v, *places)),
+            BpmE::NA => None,
+        }
+    }
+
+    // Convert a whole number of (10^-places) bpm to a whole number of
+    // 10^-MAX_PRECISION bpm, rounding any extra places half away from zero
+    fn rescale(value: i64, places: u32) -> i128 {
+        let value = i128::from(value);
+        if places <= Self::MAX_PRECISION {
+            return value * 10i128.pow(Self::MAX_PRECISION - places);
+        }
+        match 10i128.checked_pow(places - Self::MAX_PRECISION) {
+            Some(unit) => (value + value.signum() * (unit / 2)) / unit,
+            None => 0,
+        }
+    }
+}
+
+// Tempos are ordered by value, with NA after all of them
+impl Ord for BpmE {
+    fn cmp(&self, other: &BpmE) -> Ordering {
+        match (self.scaled(), other.scaled()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+}
+
+impl PartialOrd for BpmE {
+    fn partial_cmp(&self, other: &BpmE) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 impl fmt::Display for BpmE {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             BpmE::Bpm(tmpo) => write!(f, "{}", tmpo),
+            BpmE::Fractional(v, places) if *places > Self::MAX_PRECISION => {
+                write!(f, "{}", BpmE::fractional(*v, *places))
+            }
+            BpmE::Fractional(v, places) => {
+                let unit = 10i64.pow(*places);
+                let sign = if *v < 0 { "-" } else { "" };
+                write!(
+                    f,
+                    "{}{}.{:0width$}",
+                    sign,
+                    (v / unit).abs(),
+                    (v % unit).abs(),
+                    width = *places as usize
+                )
+            }
             BpmE::NA => write!(f, "na"),
         }
     }
 }
 
+// The meter of a track, i.e. whether the beats are grouped in twos/fours, or threes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MeterE {
+    Duple,
+    Triple,
+}
+
+impl MeterE {
+    pub fn parse(st: &str) -> Option<MeterE> {
+        match st {
+            "2" | "4" => Some(MeterE::Duple),
+            "3" => Some(MeterE::Triple),
+            _ => {
+                error!("Could not parse a meter from {}", st);
+                None
+            }
+        }
+    }
+
+    // The (conventional) number of beats in a bar
+    pub fn beats_per_bar(&self) -> u32 {
+        match self {
+            MeterE::Duple => 4,
+            MeterE::Triple => 3,
+        }
+    }
+}
+
+impl fmt::Display for MeterE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.beats_per_bar())
+    }
+}
+
+// A candidate tempo reported by an estimator, along with its relative strength.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TempoCandidate {
+    pub bpm: f64,
+    pub strength: f64,
+}
+
+// A record of the octave correction stage having been run over an estimate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OctaveCorrection {
+    pub original_bpm: f64, // the tempo originally reported by the estimator
+    pub factor: f64,       // the multiple applied to the original tempo
+}
+
+impl OctaveCorrection {
+    pub fn applied(&self) -> bool {
+        self.factor != 1.0
+    }
+}
+
+// Which version of an estimator produced an estimate, and how it was configured,
+// so that we can tell when a cached estimate is out of date.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub version: String, // the version of the estimator
+    pub params: String,  // a fingerprint of the parameters that it was built with
+}
+
+// The (structured) result of running a tempo estimator over a track.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TempoEstimate {
+    pub bpm: f64,                        // the (fractional) estimated tempo
+    pub confidence: Option<f64>,         // how confident the estimator is, in [0, 1], if known
+    pub candidates: Vec<TempoCandidate>, // candidate tempos, strongest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correction: Option<OctaveCorrection>, // present if octave correction was run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>, // where the estimate came from, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimated_at: Option<u64>, // when the estimate was made (seconds since the epoch), if known
+}
+
+impl TempoEstimate {
+    // An estimate with a single candidate, for estimators that report nothing else.
+    pub fn from_bpm(bpm: f64, confidence: Option<f64>) -> TempoEstimate {
+        TempoEstimate {
+            bpm,
+            confidence,
+            candidates: vec![TempoCandidate { bpm, strength: 1.0 }],
+            correction: None,
+            provenance: None,
+            estimated_at: None,
+        }
+    }
+
+    // An estimate from a set of (unranked) candidates. The strongest candidate
+    // is taken as the tempo, and the confidence is its share of the total strength.
+    pub fn from_candidates(mut candidates: Vec<TempoCandidate>) -> Option<TempoEstimate> {
+        candidates.retain(|c| c.bpm > 0.0 && c.strength.is_finite() && c.strength >= 0.0);
+        candidates.sort_by(|a, b| b.strength.partial_cmp(&a.strength).unwrap());
+        let best = candidates.first()?.clone();
+        let total: f64 = candidates.iter().map(|c| c.strength).sum();
+        let confidence = if total > 0.0 {
+            Some(best.strength / total)
+        } else {
+            None
+        };
+        Some(TempoEstimate {
+            bpm: best.bpm,
+            confidence,
+            candidates,
+            correction: None,
+            provenance: None,
+            estimated_at: None,
+        })
+    }
+
+    // Whether the estimate was made by the given version and configuration of an
+    // estimator. Estimates of unknown provenance (e.g. from comments) are never current.
+    pub fn is_current(&self, provenance: &Provenance) -> bool {
+        self.provenance.as_ref() == Some(provenance)
+    }
+
+    // The tempo of the estimate, to as many places as we store
+    pub fn to_bpm(&self) -> BpmE {
+        BpmE::from_f64(self.bpm, BpmE::MAX_PRECISION)
+    }
+}
+
 pub enum UpdateBehaviour {
     FailIfNone,
     Append,
@@ -0,0 +1,109 @@
+extern crate ellington;
+extern crate tempfile;
+
+use ellington::analysis::curve::*;
+use ellington::analysis::swing::SwingAnalyser;
+use ellington::estimators::context::AnalysisContext;
+use ellington::estimators::octave::SwingPrior;
+use ellington::estimators::registry;
+use ellington::estimators::EstimatorParams;
+use ellington::synth::*;
+
+fn estimate(name: &str, params: &EstimatorParams, context: &AnalysisContext) -> Option<f64> {
+    registry::lookup(name)
+        .unwrap()
+        .estimate(params, context)
+        .map(|e| e.bpm)
+}
+
+fn assert_close(name: &str, estimated: Option<f64>, expected: f64, tolerance: f64) {
+    let estimated = estimated.unwrap_or_else(|| panic!("{} gave no tempo", name));
+    assert!(
+        (estimated - expected).abs() <= tolerance,
+        "{} estimated {}, expected {} (+/- {})",
+        name,
+        estimated,
+        expected,
+        tolerance
+    );
+}
+
+#[test]
+fn estimators_on_click_tracks() {
+    let params = EstimatorParams::default();
+    for bpm in [90.0, 120.0, 143.0].iter() {
+        let context = AnalysisContext::from_samples(render(&clicks(*bpm, 20.0), 20.0), &params);
+        for name in ["onset", "comb"].iter() {
+            assert_close(name, estimate(name, &params, &context), *bpm, 2.0);
+        }
+    }
+}
+
+#[test]
+fn estimators_on_swing_patterns() {
+    let params = EstimatorParams {
+        octave_correction: Some(SwingPrior::default()),
+        ..EstimatorParams::default()
+    };
+    for bpm in [120.0, 160.0].iter() {
+        let samples = render(&swing_pattern(*bpm, 2.0, 30.0), 30.0);
+        let context = AnalysisContext::from_samples(samples, &params);
+        for name in ["onset", "comb"].iter() {
+            assert_close(name, estimate(name, &params, &context), *bpm, 3.0);
+        }
+    }
+}
+
+#[test]
+fn swing_ratio_of_drum_pattern() {
+    let params = EstimatorParams::default();
+    let samples = render(&swing_pattern(160.0, 2.0, 30.0), 30.0);
+    let swing = SwingAnalyser::default()
+        .analyse(&samples, &params, Some(160.0))
+        .unwrap();
+    assert!(
+        (swing.ratio - 2.0).abs() < 0.3,
+        "Swing ratio {}",
+        swing.ratio
+    );
+}
+
+#[test]
+fn curve_follows_tempo_ramp() {
+    let params = EstimatorParams::default();
+    let factory = registry::lookup("onset").unwrap();
+    let samples = render(&tempo_ramp(110.0, 150.0, 120.0), 120.0);
+    let curve = TempoCurve::analyse(
+        &*factory.build(&params),
+        factory.algorithm(),
+        &samples,
+        &CurveParams::default(),
+    )
+    .unwrap();
+    assert!(
+        (curve.summary.start_bpm - 115.0).abs() < 5.0,
+        "Curve started at {}",
+        curve.summary.start_bpm
+    );
+    assert!(
+        (curve.summary.end_bpm - 145.0).abs() < 5.0,
+        "Curve ended at {}",
+        curve.summary.end_bpm
+    );
+}
+
+// Decoding needs ffmpeg's libraries (through hodges)
+#[cfg(feature = "extended_tests")]
+#[test]
+fn estimators_on_wav_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let params = EstimatorParams::default();
+    for bpm in [100.0, 160.0].iter() {
+        let path = dir.path().join(format!("clicks-{}.wav", bpm));
+        wav::write(&path, &render(&clicks(*bpm, 20.0), 20.0)).unwrap();
+        let context = AnalysisContext::new(&path, &params);
+        for name in ["naive", "onset", "comb"].iter() {
+            assert_close(name, estimate(name, &params, &context), *bpm, 2.0);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+/*
+    A stub estimator worker, that speaks the worker protocol (see
+    shelltools::worker) without doing any analysis, for testing. It decides
+    how to respond to each request from the name of the file:
+
+    - files containing "crash" make the worker exit without responding
+    - files containing "hang" make the worker stop responding
+    - files containing "error" get an error response
+    - files containing "count" get the number of requests this worker has handled
+    - otherwise, the tempo is the first number in the file name (or 120)
+*/
+#[macro_use]
+extern crate serde_json;
+
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    // the protocol version to report can be overridden, to test the handshake
+    let protocol: u64 = std::env::args()
+        .nth(1)
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(1);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "{}", json!({"ready": true, "protocol": protocol})).unwrap();
+    out.flush().unwrap();
+
+    let stdin = io::stdin();
+    let mut handled = 0;
+    for line in stdin.lock().lines() {
+        let request: Value = match line.ok().and_then(|l| serde_json::from_str(&l).ok()) {
+            Some(r) => r,
+            None => continue,
+        };
+        handled += 1;
+        let id = request["id"].clone();
+        let file = request["file"].as_str().unwrap_or("").to_string();
+
+        let response = if file.contains("crash") {
+            process::exit(1);
+        } else if file.contains("hang") {
+            thread::sleep(Duration::from_secs(3600));
+            continue;
+        } else if file.contains("error") {
+            json!({"id": id, "error": "could not analyse file"})
+        } else if file.contains("count") {
+            json!({"id": id, "bpm": handled})
+        } else {
+            let digits: String = file
+                .chars()
+                .skip_while(|c| !c.is_ascii_digit())
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            json!({"id": id, "bpm": digits.parse::<f64>().unwrap_or(120.0)})
+        };
+        writeln!(out, "{}", response).unwrap();
+        out.flush().unwrap();
+    }
+}
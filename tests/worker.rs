@@ -0,0 +1,63 @@
+extern crate ellington;
+
+use ellington::shelltools::worker::*;
+use std::path::Path;
+
+// The stub worker (in tests/support) is a binary of the crate, which cargo
+// builds before running these tests.
+const STUB_WORKER: &str = env!("CARGO_BIN_EXE_ellington-stub-worker");
+
+fn stub_worker(args: &[&str]) -> Worker {
+    let mut command = vec![STUB_WORKER.to_string()];
+    command.extend(args.iter().map(|a| a.to_string()));
+    let mut config = WorkerConfig::new(command);
+    config.startup_timeout = 10.0;
+    config.request_timeout = 1.0;
+    config.max_restarts = 1;
+    Worker::new(config)
+}
+
+fn bpm(worker: &mut Worker, file: &str) -> Option<f64> {
+    worker.request(Path::new(file)).and_then(|r| r.bpm)
+}
+
+#[test]
+fn worker_is_reused() {
+    let mut worker = stub_worker(&[]);
+    assert_eq!(bpm(&mut worker, "/music/track-143.mp3"), Some(143.0));
+    assert_eq!(bpm(&mut worker, "/music/count.mp3"), Some(2.0));
+    assert_eq!(bpm(&mut worker, "/music/count.mp3"), Some(3.0));
+}
+
+#[test]
+fn worker_errors() {
+    let mut worker = stub_worker(&[]);
+    let response = worker.request(Path::new("/music/error.mp3")).unwrap();
+    assert_eq!(response.bpm, None);
+    assert!(response.error.is_some());
+}
+
+#[test]
+fn worker_restarts_after_crash() {
+    let mut worker = stub_worker(&[]);
+    assert_eq!(bpm(&mut worker, "/music/count.mp3"), Some(1.0));
+    assert!(worker.request(Path::new("/music/crash.mp3")).is_none());
+    // a new worker has been started, so the count starts again
+    assert_eq!(bpm(&mut worker, "/music/count.mp3"), Some(1.0));
+    // but we only restart it so many times
+    assert!(worker.request(Path::new("/music/crash.mp3")).is_none());
+    assert!(worker.request(Path::new("/music/count.mp3")).is_none());
+}
+
+#[test]
+fn worker_times_out() {
+    let mut worker = stub_worker(&[]);
+    assert!(worker.request(Path::new("/music/hang.mp3")).is_none());
+    assert_eq!(bpm(&mut worker, "/music/count.mp3"), Some(1.0));
+}
+
+#[test]
+fn worker_handshake() {
+    let mut worker = stub_worker(&["2"]);
+    assert!(worker.request(Path::new("/music/track-143.mp3")).is_none());
+}